@@ -1,9 +1,56 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use csv::Writer;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use mime_guess;
 use serde::Serialize;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 
+use crate::cache::STATE_FILE;
+use crate::downloader::file_digests;
+use crate::managed::MANAGED_FILE;
+use crate::schema::{DrupalFileAsset, DrupalFileAssetsWrapper, DrupalSource};
+
+/// Paths left out of listings unless `--no-default-excludes` is given,
+/// including the state files a sync keeps in its destination
+const DEFAULT_EXCLUDES: &[&str] = &[
+    ".git/",
+    "node_modules/",
+    "*.tmp",
+    "*.swp",
+    ".*.part",
+    ".DS_Store",
+    "Thumbs.db",
+    STATE_FILE,
+    MANAGED_FILE,
+    crate::BASELINE_FILE,
+];
+
+/// File format of a generated asset listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ListingFormat {
+    /// Bare JSON array of listing entries
+    Json,
+    Csv,
+    Ndjson,
+    /// Metadata wrapper that `sync` can read as its manifest
+    Manifest,
+}
+
+impl ListingFormat {
+    /// File name used when no explicit output file is given
+    pub fn default_file_name(&self) -> &'static str {
+        match self {
+            ListingFormat::Json => "assets.json",
+            ListingFormat::Csv => "assets.csv",
+            ListingFormat::Ndjson => "assets.ndjson",
+            ListingFormat::Manifest => "manifest.json",
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct AssetEntry {
     pub filename: String,
@@ -11,12 +58,62 @@ pub struct AssetEntry {
     pub size: u64,
     pub mime_type: String,
     pub download_url: Option<String>,
+    /// Hex SHA-256 of the content, when checksums were requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Unix modification time, carried into manifests only
+    #[serde(skip)]
+    pub modified: i64,
 }
 
 #[derive(Debug)]
 pub struct AssetListingConfig {
     pub base_url: Option<String>,
+    /// Directory the listing is written to when `output_file` is unset
     pub output_path: PathBuf,
+    /// Explicit file to write the listing to
+    pub output_file: Option<PathBuf>,
+    pub format: ListingFormat,
+    /// List the targets of symlinks instead of skipping them
+    pub follow_symlinks: bool,
+    /// Directory levels to descend below the listed directory; unlimited when unset
+    pub max_depth: Option<usize>,
+    /// Gitignore-style file of paths to leave out
+    pub ignore_file: Option<PathBuf>,
+    /// Leave out `DEFAULT_EXCLUDES`
+    pub default_excludes: bool,
+    /// Hash every file's content into `AssetEntry::checksum`
+    pub checksums: bool,
+    /// Files read at once while building entries
+    pub concurrency: usize,
+}
+
+impl AssetListingConfig {
+    /// Builds the matcher for paths under `root` that the listing skips
+    fn ignore_matcher(&self, root: &Path) -> Result<Gitignore> {
+        let mut builder = GitignoreBuilder::new(root);
+        if self.default_excludes {
+            for pattern in DEFAULT_EXCLUDES {
+                builder.add_line(None, pattern)?;
+            }
+        }
+        if let Some(path) = &self.ignore_file {
+            if !path.is_file() {
+                anyhow::bail!("Ignore file {} not found", path.display());
+            }
+            if let Some(e) = builder.add(path) {
+                return Err(e).context(format!("Failed to read ignore file {}", path.display()));
+            }
+        }
+        builder.build().context("Failed to build ignore rules")
+    }
+
+    /// Resolves the file the listing will be written to
+    pub fn output_file(&self) -> PathBuf {
+        self.output_file
+            .clone()
+            .unwrap_or_else(|| self.output_path.join(self.format.default_file_name()))
+    }
 }
 
 impl AssetEntry {
@@ -37,6 +134,12 @@ impl AssetEntry {
             )
         });
 
+        let checksum = if config.checksums {
+            Some(file_digests(path, false).await?.0)
+        } else {
+            None
+        };
+
         Ok(Self {
             filename: path
                 .file_name()
@@ -46,28 +149,136 @@ impl AssetEntry {
             size: metadata.len(),
             mime_type,
             download_url,
+            checksum,
+            modified: filetime::FileTime::from_last_modification_time(&metadata).unix_seconds(),
         })
     }
+
+    /// The entry as a manifest asset identified by its relative path
+    fn to_asset(&self) -> DrupalFileAsset {
+        DrupalFileAsset {
+            id: self.path.clone(),
+            filename: self.filename.clone(),
+            uri: format!("public://{}", self.path),
+            path: self.path.clone(),
+            mime: self.mime_type.clone(),
+            size: Some(self.size),
+            created: self.modified,
+            changed: self.modified,
+            scheme: "public".to_string(),
+            tags: Vec::new(),
+            priority: None,
+            sha256: self.checksum.clone(),
+        }
+    }
 }
 
 pub async fn generate_asset_listing(
     dir_path: &Path,
     config: &AssetListingConfig,
 ) -> Result<()> {
-    let mut entries = Vec::new();
-
-    let mut read_dir = fs::read_dir(dir_path).await?;
-    while let Some(entry) = read_dir.next_entry().await? {
-        let path = entry.path();
-        if path.is_file() {
-            let asset = AssetEntry::from_path(&path, dir_path, config).await?;
-            entries.push(asset);
+    let mut files = Vec::new();
+    let ignored = config.ignore_matcher(dir_path)?;
+    // A previous listing written inside the directory isn't an asset
+    let output_file = config.output_file();
+    let previous_output = fs::canonicalize(&output_file).await.ok();
+
+    // Depth-first with an explicit stack; followed symlinks can form cycles,
+    // so each directory is entered once
+    let mut visited = HashSet::new();
+    let mut stack = vec![(dir_path.to_path_buf(), 0)];
+    while let Some((dir, depth)) = stack.pop() {
+        if let Ok(canonical) = fs::canonicalize(&dir).await {
+            if !visited.insert(canonical) {
+                continue;
+            }
+        }
+        let mut read_dir = fs::read_dir(&dir)
+            .await
+            .context(format!("Failed to read directory {}", dir.display()))?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let mut file_type = entry.file_type().await?;
+            if file_type.is_symlink() {
+                if !config.follow_symlinks {
+                    continue;
+                }
+                match fs::metadata(&path).await {
+                    Ok(target) => file_type = target.file_type(),
+                    // Dangling link
+                    Err(_) => continue,
+                }
+            }
+            // Skipping an ignored directory skips everything below it
+            if ignored.matched(&path, file_type.is_dir()).is_ignore() {
+                continue;
+            }
+            if file_type.is_dir() {
+                if config.max_depth.map_or(true, |max_depth| depth < max_depth) {
+                    stack.push((path, depth + 1));
+                }
+            } else if file_type.is_file() {
+                if let Some(previous) = &previous_output {
+                    if path.file_name() == previous.file_name()
+                        && fs::canonicalize(&path).await.ok().as_ref() == Some(previous)
+                    {
+                        continue;
+                    }
+                }
+                files.push(path);
+            }
         }
     }
 
-    let json = serde_json::to_string_pretty(&entries)?;
-    let output_file = config.output_path.join("assets.json");
-    fs::write(output_file, json).await?;
+    let mut entries: Vec<AssetEntry> = stream::iter(files)
+        .map(|path| async move { AssetEntry::from_path(&path, dir_path, config).await })
+        .buffer_unordered(config.concurrency.max(1))
+        .try_collect()
+        .await?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
 
+    write_listing(&entries, config.format, &output_file)
+        .await
+        .context(format!("Failed to write asset listing {}", output_file.display()))?;
+
+    Ok(())
+}
+
+/// Writes the entries in the requested format
+async fn write_listing(entries: &[AssetEntry], format: ListingFormat, output_file: &Path) -> Result<()> {
+    match format {
+        ListingFormat::Json => {
+            let json = serde_json::to_string_pretty(entries)?;
+            fs::write(output_file, json).await?;
+        }
+        ListingFormat::Csv => {
+            let mut wtr = Writer::from_path(output_file)?;
+            for entry in entries {
+                wtr.serialize(entry)?;
+            }
+            wtr.flush()?;
+        }
+        ListingFormat::Ndjson => {
+            let mut lines = String::new();
+            for entry in entries {
+                lines.push_str(&serde_json::to_string(entry)?);
+                lines.push('\n');
+            }
+            fs::write(output_file, lines).await?;
+        }
+        ListingFormat::Manifest => {
+            let manifest = DrupalFileAssetsWrapper {
+                version: "1.0".to_string(),
+                generated: chrono::Utc::now().timestamp(),
+                source: DrupalSource {
+                    source_type: "filesystem".to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                },
+                files: entries.iter().map(AssetEntry::to_asset).collect(),
+                directories: Vec::new(),
+            };
+            fs::write(output_file, serde_json::to_string_pretty(&manifest)?).await?;
+        }
+    }
     Ok(())
 }
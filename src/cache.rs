@@ -0,0 +1,107 @@
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+/// Default cache file name, kept in the first destination
+pub const STATE_FILE: &str = ".sync-state.json";
+
+/// Validators from the last successful download of an asset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CacheEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl CacheEntry {
+    /// Validators sent with a response, if any
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+}
+
+/// Conditional-request cache keyed by asset id, stored as JSON.
+///
+/// Clones share the same entries, so one cache can be handed to every
+/// download task and saved once the run is over.
+#[derive(Debug, Clone)]
+pub struct EtagCache {
+    path: PathBuf,
+    entries: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl EtagCache {
+    /// Loads the cache from `path`, starting empty when it doesn't exist
+    pub async fn load(path: &Path) -> Result<Self> {
+        let entries = if path.exists() {
+            let content = fs::read_to_string(path)
+                .await
+                .context(format!("Failed to read ETag cache {}", path.display()))?;
+            serde_json::from_str(&content)
+                .context(format!("Failed to parse ETag cache {}", path.display()))?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    pub async fn get(&self, id: &str) -> Option<CacheEntry> {
+        self.entries.lock().await.get(id).cloned()
+    }
+
+    /// Remembers the validators of a download once its content is safely
+    /// written, forgetting the asset when the response sent none
+    pub async fn record(&self, id: &str, entry: CacheEntry) {
+        let mut entries = self.entries.lock().await;
+        if entry.etag.is_some() || entry.last_modified.is_some() {
+            entries.insert(id.to_string(), entry);
+        } else {
+            entries.remove(id);
+        }
+    }
+
+    /// Drops an asset's validators, so its next download is unconditional
+    pub async fn forget(&self, id: &str) {
+        self.entries.lock().await.remove(id);
+    }
+
+    pub async fn len(&self) -> usize {
+        self.entries.lock().await.len()
+    }
+
+    /// Persists the cache with a write to a temp file followed by a rename
+    pub async fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .await
+                .context(format!("Failed to create directory: {}", parent.display()))?;
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        let content = serde_json::to_string_pretty(&*self.entries.lock().await)?;
+        fs::write(&tmp_path, content)
+            .await
+            .context(format!("Failed to write ETag cache {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .await
+            .context(format!("Failed to move ETag cache into place at {}", self.path.display()))?;
+        Ok(())
+    }
+}
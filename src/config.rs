@@ -2,9 +2,37 @@ use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use tokio::fs;
 
+/// Config directory override set from `--config-dir`
+static CONFIG_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Overrides the directory configurations are loaded from and saved to
+pub fn set_config_dir(path: PathBuf) {
+    let _ = CONFIG_DIR_OVERRIDE.set(path);
+}
+
+/// Longest accepted download timeout, in seconds (one day)
+const MAX_DOWNLOAD_TIMEOUT: u64 = 86_400;
+/// Longest accepted pause between downloads, in milliseconds (ten minutes)
+const MAX_DOWNLOAD_DELAY: u64 = 600_000;
+const MAX_RETRIES: usize = 100;
+
+/// Checks whether a value still holds a `${VAR}` reference
+fn has_env_reference(value: &str) -> bool {
+    value.replace("$$", "").contains("${")
+}
+
+/// Checks whether an error means the config location can't be written
+fn is_read_only_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|e| matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem))
+}
+
 /// Represents the CLI configuration for a specific destination
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CliConfig {
@@ -61,28 +89,99 @@ impl CliConfig {
 
     /// Gets the configuration directory path
     pub fn config_dir() -> Result<PathBuf> {
+        if let Some(dir) = CONFIG_DIR_OVERRIDE.get() {
+            return Ok(dir.clone());
+        }
         let proj_dirs = ProjectDirs::from("com", "althaf", "cli-file-sync")
             .context("Failed to determine project directories")?;
         Ok(proj_dirs.config_dir().to_path_buf())
     }
 
-    /// Gets the configuration file path for a specific ID
+    /// Gets the configuration file path for a specific ID, rejecting ids
+    /// that would point outside the configuration directory
     pub fn config_file(id: &str) -> Result<PathBuf> {
+        if id.trim().is_empty() || id.contains(['/', '\\']) || id.contains("..") {
+            anyhow::bail!("Invalid configuration id '{}': it must not be empty or contain '/', '\\' or '..'", id);
+        }
         let mut path = Self::config_dir()?;
         path.push(format!("{}.json", id));
         Ok(path)
     }
 
-    /// Loads configuration from file
+    /// Loads configuration from file, expanding `${VAR}` references in the
+    /// base URL, destination path and credentials
     pub async fn load(id: &str) -> Result<Self> {
+        let mut config = Self::load_raw(id).await?;
+        config
+            .expand_env()
+            .context(format!("Failed to expand configuration '{}'", id))?;
+        config
+            .validate()
+            .context(format!("Invalid configuration '{}'", id))?;
+        Ok(config)
+    }
+
+    /// Loads configuration from file as written, leaving `${VAR}` references
+    /// in place so it can be edited and saved back
+    pub async fn load_raw(id: &str) -> Result<Self> {
         let path = Self::config_file(id)?;
         let content = fs::read_to_string(path).await?;
         let config: CliConfig = serde_json::from_str(&content)?;
         Ok(config)
     }
 
+    fn expand_env(&mut self) -> Result<()> {
+        self.desti_path = expand_env_vars(&self.desti_path).context("in desti_path")?;
+        for (name, value) in [
+            ("base_url", &mut self.base_url),
+            ("source_username", &mut self.source_username),
+            ("source_password", &mut self.source_password),
+            ("download_username", &mut self.download_username),
+            ("download_password", &mut self.download_password),
+        ] {
+            if let Some(value) = value {
+                *value = expand_env_vars(value).context(format!("in {}", name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks values a sync can't run with. Fields still holding `${VAR}`
+    /// references are skipped; they are checked once expanded on load.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(base_url) = self.base_url.as_deref().filter(|url| !has_env_reference(url)) {
+            url::Url::parse(base_url).context(format!("base_url '{}' is not a valid URL", base_url))?;
+        }
+        if self.max_concurrent == 0 {
+            anyhow::bail!("max_concurrent must be at least 1");
+        }
+        if !(1..=MAX_DOWNLOAD_TIMEOUT).contains(&self.download_timeout) {
+            anyhow::bail!(
+                "download_timeout must be between 1 and {} seconds, got {}",
+                MAX_DOWNLOAD_TIMEOUT,
+                self.download_timeout
+            );
+        }
+        if self.download_delay > MAX_DOWNLOAD_DELAY {
+            anyhow::bail!(
+                "download_delay must be at most {}ms, got {}",
+                MAX_DOWNLOAD_DELAY,
+                self.download_delay
+            );
+        }
+        if self.max_retries > MAX_RETRIES {
+            anyhow::bail!("max_retries must be at most {}, got {}", MAX_RETRIES, self.max_retries);
+        }
+        if !has_env_reference(&self.desti_path) && Path::new(&self.desti_path).is_file() {
+            anyhow::bail!("desti_path '{}' is a file, not a directory", self.desti_path);
+        }
+        Ok(())
+    }
+
     /// Saves configuration to file
     pub async fn save(&self) -> Result<()> {
+        self.validate()
+            .context(format!("Invalid configuration '{}'", self.id))?;
         let path = Self::config_file(&self.id)?;
         
         // Ensure config directory exists
@@ -95,25 +194,78 @@ impl CliConfig {
         Ok(())
     }
 
+    /// Saves configuration, keeping it in memory only when the config
+    /// directory is read-only. Returns whether the config was persisted.
+    pub async fn save_or_warn(&self) -> Result<bool> {
+        match self.save().await {
+            Ok(()) => Ok(true),
+            Err(e) if is_read_only_error(&e) => {
+                let location = Self::config_dir()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_else(|_| "config directory".to_string());
+                log::warn!(
+                    "{} is not writable ({}); using in-memory configuration for this run. Use --config-dir to choose a writable location.",
+                    location, e
+                );
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     /// Updates the last sync timestamp
     pub fn update_last_sync(&mut self) {
         self.last_sync = Some(Utc::now());
     }
 
+    /// Seconds since the last successful sync, if there was one
+    pub fn seconds_since_last_sync(&self) -> Option<u64> {
+        self.last_sync.map(|last_sync| {
+            Utc::now()
+                .signed_duration_since(last_sync)
+                .num_seconds()
+                .max(0) as u64
+        })
+    }
+
     /// Checks if sync is needed based on TTL
     pub fn needs_sync(&self) -> bool {
-        match (self.ttl, self.last_sync) {
-            (Some(ttl), Some(last_sync)) => {
-                let duration = Utc::now()
-                    .signed_duration_since(last_sync)
-                    .num_seconds() as u64;
-                duration >= ttl
-            }
+        match (self.ttl, self.seconds_since_last_sync()) {
+            (Some(ttl), Some(duration)) => duration >= ttl,
             _ => true,
         }
     }
 }
 
+/// Replaces each `${VAR}` with the environment variable's value; `$$`
+/// stands for a literal `$`
+pub fn expand_env_vars(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(reference) = after.strip_prefix('{') {
+            let end = reference
+                .find('}')
+                .context(format!("Unterminated variable reference in '{}'", value))?;
+            let name = &reference[..end];
+            let resolved = std::env::var(name)
+                .context(format!("Environment variable '{}' is not set", name))?;
+            expanded.push_str(&resolved);
+            rest = &reference[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
 /// Lists all available configurations
 pub async fn list_configs() -> Result<Vec<CliConfig>> {
     let config_dir = CliConfig::config_dir()?;
@@ -138,6 +290,18 @@ pub async fn list_configs() -> Result<Vec<CliConfig>> {
     Ok(configs)
 }
 
+/// Removes a saved configuration, failing when it doesn't exist
+pub async fn delete_config(id: &str) -> Result<()> {
+    let path = CliConfig::config_file(id)?;
+    if !path.exists() {
+        anyhow::bail!("No configuration '{}' at {}", id, path.display());
+    }
+    fs::remove_file(&path)
+        .await
+        .context(format!("Failed to delete {}", path.display()))?;
+    Ok(())
+}
+
 /// Validates a destination path
 pub async fn validate_desti_path(path: &Path) -> Result<()> {
     if !path.exists() {
@@ -4,7 +4,8 @@ use std::path::PathBuf;
 use tokio::fs;
 use csv::Writer;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
 pub enum UserRole {
     All,
     Developer,
@@ -21,6 +22,19 @@ impl std::fmt::Display for UserRole {
     }
 }
 
+impl UserRole {
+    /// Checks whether this role may read items meant for `item_role`:
+    /// admin sees everything, developer sees developer and general
+    /// items, and all sees only general items
+    pub fn can_view(&self, item_role: UserRole) -> bool {
+        match self {
+            UserRole::Admin => true,
+            UserRole::Developer => item_role != UserRole::Admin,
+            UserRole::All => item_role == UserRole::All,
+        }
+    }
+}
+
 /// Base trait for documentation items
 trait DocItem: Serialize {
     fn role(&self) -> UserRole;
@@ -184,7 +198,71 @@ pub struct RolePermission {
     description: String,
 }
 
-/// Generates documentation in CSV format
+impl DocItem for RolePermission {
+    fn role(&self) -> UserRole {
+        match self.role.as_str() {
+            "admin" => UserRole::Admin,
+            "developer" => UserRole::Developer,
+            _ => UserRole::All,
+        }
+    }
+}
+
+/// Every documentation table in one document, keyed by section
+#[derive(Debug, Serialize)]
+pub struct CombinedDocs {
+    pub role: UserRole,
+    pub user_guides: Vec<UserGuideDoc>,
+    pub setup_guides: Vec<SetupGuideDoc>,
+    pub reports: Vec<ReportDoc>,
+    pub troubleshooting: Vec<TroubleshootingDoc>,
+    pub technical_docs: Vec<TechnicalDoc>,
+    pub commands: Vec<CommandDoc>,
+    pub parameters: Vec<ParameterDoc>,
+    pub configs: Vec<ConfigDoc>,
+    pub role_permissions: Vec<RolePermission>,
+}
+
+/// Keeps the items `role` may read
+fn visible_to<T: DocItem>(items: Vec<T>, role: UserRole) -> Vec<T> {
+    items
+        .into_iter()
+        .filter(|item| role.can_view(item.role()))
+        .collect()
+}
+
+/// File format of generated documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DocFormat {
+    Csv,
+    Json,
+    Markdown,
+}
+
+/// Formats written as one file per table; Markdown is a single docs.md instead
+#[derive(Debug, Clone, Copy)]
+enum TableFormat {
+    Csv,
+    Json,
+}
+
+/// Escapes a value for a Markdown table cell
+fn md_cell(value: &str) -> String {
+    value.replace('|', "\\|").replace('\n', "<br>")
+}
+
+/// Appends a Markdown table with `headers` and one row per entry of `rows`
+fn md_table(out: &mut String, headers: &[&str], rows: Vec<Vec<String>>) {
+    out.push_str(&format!("| {} |\n", headers.join(" | ")));
+    out.push_str(&format!("|{}\n", " --- |".repeat(headers.len())));
+    for row in rows {
+        let cells: Vec<String> = row.iter().map(|cell| md_cell(cell)).collect();
+        out.push_str(&format!("| {} |\n", cells.join(" | ")));
+    }
+    out.push('\n');
+}
+
+/// Generates documentation in CSV, JSON or Markdown format
 pub struct DocGenerator {
     output_dir: PathBuf,
 }
@@ -194,30 +272,218 @@ impl DocGenerator {
         Self { output_dir }
     }
 
-    /// Generates all documentation tables
-    pub async fn generate_docs(&self) -> Result<()> {
+    /// Generates all documentation tables, one file per table, keeping
+    /// only the rows `role` may read
+    pub async fn generate_docs(&self, format: DocFormat, role: UserRole) -> Result<()> {
+        let format = match format {
+            DocFormat::Csv => TableFormat::Csv,
+            DocFormat::Json => TableFormat::Json,
+            DocFormat::Markdown => {
+                self.generate_markdown_file(role).await?;
+                return Ok(());
+            }
+        };
         fs::create_dir_all(&self.output_dir).await?;
+        let docs = self.combined(role);
         
         // Generate user documentation
-        self.generate_user_guides().await?;
-        self.generate_setup_guides().await?;
-        self.generate_reports().await?;
-        self.generate_troubleshooting().await?;
+        self.write_table("user_guides", &docs.user_guides, format).await?;
+        self.write_table("setup_guides", &docs.setup_guides, format).await?;
+        self.write_table("reports", &docs.reports, format).await?;
+        self.write_table("troubleshooting", &docs.troubleshooting, format).await?;
         
         // Generate developer documentation
-        self.generate_technical_docs().await?;
-        self.generate_commands().await?;
-        self.generate_parameters().await?;
-        self.generate_configs().await?;
+        self.write_table("technical_docs", &docs.technical_docs, format).await?;
+        self.write_table("commands", &docs.commands, format).await?;
+        self.write_table("parameters", &docs.parameters, format).await?;
+        self.write_table("configs", &docs.configs, format).await?;
         
         // Generate role permissions
-        self.generate_roles().await?;
+        self.write_table("role_permissions", &docs.role_permissions, format).await?;
         
         Ok(())
     }
 
-    async fn generate_user_guides(&self) -> Result<()> {
-        let guides = vec![
+    /// Collects every table `role` may read into one document
+    pub fn combined(&self, role: UserRole) -> CombinedDocs {
+        CombinedDocs {
+            role,
+            user_guides: visible_to(Self::user_guides(), role),
+            setup_guides: visible_to(Self::setup_guides(), role),
+            reports: visible_to(Self::reports(), role),
+            troubleshooting: visible_to(Self::troubleshooting(), role),
+            technical_docs: visible_to(Self::technical_docs(), role),
+            commands: visible_to(Self::commands(), role),
+            parameters: visible_to(Self::parameters(), role),
+            configs: visible_to(Self::configs(), role),
+            role_permissions: visible_to(Self::roles(), role),
+        }
+    }
+
+    /// Writes the combined documentation for `role` to `docs.json`
+    pub async fn generate_combined_json(&self, role: UserRole) -> Result<PathBuf> {
+        fs::create_dir_all(&self.output_dir).await?;
+        let path = self.output_dir.join("docs.json");
+        let json = serde_json::to_string_pretty(&self.combined(role))?;
+        fs::write(&path, json).await?;
+        Ok(path)
+    }
+
+    /// Writes the Markdown documentation for `role` to `docs.md`
+    pub async fn generate_markdown_file(&self, role: UserRole) -> Result<PathBuf> {
+        fs::create_dir_all(&self.output_dir).await?;
+        let path = self.output_dir.join("docs.md");
+        fs::write(&path, self.generate_markdown(role)).await?;
+        Ok(path)
+    }
+
+    /// Renders the documentation `role` may read as one Markdown document:
+    /// guides as prose, reference material as tables. Sections with no
+    /// visible entries are left out.
+    pub fn generate_markdown(&self, role: UserRole) -> String {
+        let mut docs = self.combined(role);
+        let mut out = format!("# cli-file-sync Reference\n\nAudience: `{}`\n\n", role);
+
+        docs.user_guides.sort_by_key(|guide| guide.order);
+        if !docs.user_guides.is_empty() {
+            out.push_str("## User Guides\n\n");
+            for guide in &docs.user_guides {
+                out.push_str(&format!("### {}\n\n_{}_\n\n{}\n\n", guide.title, guide.category, guide.content));
+            }
+        }
+
+        docs.setup_guides.sort_by_key(|guide| guide.order);
+        if !docs.setup_guides.is_empty() {
+            out.push_str("## Setup Guides\n\n");
+            for guide in &docs.setup_guides {
+                out.push_str(&format!(
+                    "### {}\n\n**Prerequisites:** {}\n\n{}\n\n",
+                    guide.title, guide.prerequisites, guide.steps
+                ));
+            }
+        }
+
+        if !docs.commands.is_empty() {
+            out.push_str("## Commands\n\n");
+            let rows = docs
+                .commands
+                .iter()
+                .map(|cmd| {
+                    vec![
+                        format!("`{}`", cmd.name),
+                        cmd.description.clone(),
+                        format!("`{}`", cmd.usage_example),
+                        cmd.category.clone(),
+                    ]
+                })
+                .collect();
+            md_table(&mut out, &["Command", "Description", "Usage", "Category"], rows);
+        }
+
+        if !docs.parameters.is_empty() {
+            out.push_str("## Parameters\n\n");
+            let command_name = |id: &str| {
+                Self::commands()
+                    .into_iter()
+                    .find(|cmd| cmd.id == id)
+                    .map_or_else(|| id.to_string(), |cmd| cmd.name)
+            };
+            let rows = docs
+                .parameters
+                .iter()
+                .map(|param| {
+                    vec![
+                        command_name(&param.command_id),
+                        format!("`--{}`", param.name),
+                        param.description.clone(),
+                        param.data_type.clone(),
+                        param.default_value.clone().unwrap_or_default(),
+                        if param.is_required { "yes" } else { "no" }.to_string(),
+                    ]
+                })
+                .collect();
+            md_table(&mut out, &["Command", "Parameter", "Description", "Type", "Default", "Required"], rows);
+        }
+
+        if !docs.configs.is_empty() {
+            out.push_str("## Configuration\n\n");
+            let rows = docs
+                .configs
+                .iter()
+                .map(|cfg| {
+                    vec![
+                        format!("`{}`", cfg.name),
+                        cfg.description.clone(),
+                        cfg.data_type.clone(),
+                        cfg.default_value.clone().unwrap_or_default(),
+                        cfg.category.clone(),
+                    ]
+                })
+                .collect();
+            md_table(&mut out, &["Setting", "Description", "Type", "Default", "Category"], rows);
+        }
+
+        if !docs.reports.is_empty() {
+            out.push_str("## Reports\n\n");
+            let rows = docs
+                .reports
+                .iter()
+                .map(|report| {
+                    vec![
+                        report.name.clone(),
+                        report.description.clone(),
+                        report.format.clone(),
+                        format!("`{}`", report.fields),
+                    ]
+                })
+                .collect();
+            md_table(&mut out, &["Report", "Description", "Format", "Fields"], rows);
+        }
+
+        if !docs.troubleshooting.is_empty() {
+            out.push_str("## Troubleshooting\n\n");
+            for item in &docs.troubleshooting {
+                out.push_str(&format!(
+                    "### {}\n\n{}\n\nRelated errors: {}\n\n",
+                    item.issue, item.solution, item.related_errors
+                ));
+            }
+        }
+
+        if !docs.technical_docs.is_empty() {
+            out.push_str("## Technical Documentation\n\n");
+            for doc in &docs.technical_docs {
+                out.push_str(&format!(
+                    "### {}\n\n{}\n\nRelated files: `{}`\n\n",
+                    doc.title, doc.content, doc.related_files
+                ));
+            }
+        }
+
+        if !docs.role_permissions.is_empty() {
+            out.push_str("## Role Permissions\n\n");
+            let rows = docs
+                .role_permissions
+                .iter()
+                .map(|perm| {
+                    vec![
+                        perm.role.clone(),
+                        perm.resource.clone(),
+                        perm.permissions.clone(),
+                        perm.description.clone(),
+                    ]
+                })
+                .collect();
+            md_table(&mut out, &["Role", "Resource", "Permissions", "Description"], rows);
+        }
+
+        out.truncate(out.trim_end().len());
+        out.push('\n');
+        out
+    }
+
+    fn user_guides() -> Vec<UserGuideDoc> {
+        vec![
             UserGuideDoc {
                 id: "ug_basic".to_string(),
                 title: "Basic Usage".to_string(),
@@ -234,13 +500,11 @@ impl DocGenerator {
                 role: "all".to_string(),
                 order: 2,
             },
-        ];
-
-        self.write_csv("user_guides.csv", &guides).await
+        ]
     }
 
-    async fn generate_setup_guides(&self) -> Result<()> {
-        let guides = vec![
+    fn setup_guides() -> Vec<SetupGuideDoc> {
+        vec![
             SetupGuideDoc {
                 id: "setup_basic".to_string(),
                 title: "Basic Setup".to_string(),
@@ -250,13 +514,11 @@ impl DocGenerator {
                 role: "all".to_string(),
                 order: 1,
             },
-        ];
-
-        self.write_csv("setup_guides.csv", &guides).await
+        ]
     }
 
-    async fn generate_technical_docs(&self) -> Result<()> {
-        let docs = vec![
+    fn technical_docs() -> Vec<TechnicalDoc> {
+        vec![
             TechnicalDoc {
                 id: "tech_arch".to_string(),
                 title: "Architecture Overview".to_string(),
@@ -265,13 +527,11 @@ impl DocGenerator {
                 role: "developer".to_string(),
                 related_files: "main.rs,sync.rs".to_string(),
             },
-        ];
-
-        self.write_csv("technical_docs.csv", &docs).await
+        ]
     }
 
-    async fn generate_reports(&self) -> Result<()> {
-        let reports = vec![
+    fn reports() -> Vec<ReportDoc> {
+        vec![
             ReportDoc {
                 id: "report_sync".to_string(),
                 name: "Sync Report".to_string(),
@@ -281,13 +541,11 @@ impl DocGenerator {
                 role: "all".to_string(),
                 category: "Operations".to_string(),
             },
-        ];
-
-        self.write_csv("reports.csv", &reports).await
+        ]
     }
 
-    async fn generate_troubleshooting(&self) -> Result<()> {
-        let guides = vec![
+    fn troubleshooting() -> Vec<TroubleshootingDoc> {
+        vec![
             TroubleshootingDoc {
                 id: "trouble_conn".to_string(),
                 issue: "Connection Failed".to_string(),
@@ -296,13 +554,11 @@ impl DocGenerator {
                 role: "all".to_string(),
                 related_errors: "E001,E002".to_string(),
             },
-        ];
-
-        self.write_csv("troubleshooting.csv", &guides).await
+        ]
     }
 
-    async fn generate_roles(&self) -> Result<()> {
-        let permissions = vec![
+    fn roles() -> Vec<RolePermission> {
+        vec![
             RolePermission {
                 role: "all".to_string(),
                 resource: "user_guides".to_string(),
@@ -315,13 +571,11 @@ impl DocGenerator {
                 permissions: "read".to_string(),
                 description: "Access to technical documentation".to_string(),
             },
-        ];
-
-        self.write_csv("role_permissions.csv", &permissions).await
+        ]
     }
 
-    async fn generate_commands(&self) -> Result<()> {
-        let commands = vec![
+    fn commands() -> Vec<CommandDoc> {
+        vec![
             CommandDoc {
                 id: "cmd_sync".to_string(),
                 name: "sync".to_string(),
@@ -340,13 +594,11 @@ impl DocGenerator {
                 role: "all".to_string(),
                 is_required: false,
             },
-        ];
-
-        self.write_csv("commands.csv", &commands).await
+        ]
     }
 
-    async fn generate_parameters(&self) -> Result<()> {
-        let parameters = vec![
+    fn parameters() -> Vec<ParameterDoc> {
+        vec![
             ParameterDoc {
                 id: "param_assets_source".to_string(),
                 command_id: "cmd_sync".to_string(),
@@ -367,13 +619,11 @@ impl DocGenerator {
                 role: "all".to_string(),
                 is_required: false,
             },
-        ];
-
-        self.write_csv("parameters.csv", &parameters).await
+        ]
     }
 
-    async fn generate_configs(&self) -> Result<()> {
-        let configs = vec![
+    fn configs() -> Vec<ConfigDoc> {
+        vec![
             ConfigDoc {
                 id: "cfg_base_url".to_string(),
                 name: "base_url".to_string(),
@@ -392,9 +642,23 @@ impl DocGenerator {
                 category: "Download".to_string(),
                 role: "all".to_string(),
             },
-        ];
+        ]
+    }
 
-        self.write_csv("configs.csv", &configs).await
+    async fn write_table<T: serde::Serialize>(
+        &self,
+        name: &str,
+        data: &[T],
+        format: TableFormat,
+    ) -> Result<()> {
+        match format {
+            TableFormat::Csv => self.write_csv(&format!("{}.csv", name), data).await,
+            TableFormat::Json => {
+                let path = self.output_dir.join(format!("{}.json", name));
+                fs::write(&path, serde_json::to_string_pretty(data)?).await?;
+                Ok(())
+            }
+        }
     }
 
     async fn write_csv<T: serde::Serialize>(
@@ -1,24 +1,330 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::Serialize;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::fs;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tokio::time::sleep;
 use reqwest::header::AUTHORIZATION;
 use base64::Engine;
+use filetime::FileTime;
 use base64::engine::general_purpose::STANDARD as base64_engine;
 use chrono;
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
 
-use crate::schema::DrupalFileAsset;
+use crate::cache::{CacheEntry, EtagCache};
+use crate::progress::SyncProgress;
+use crate::reporting::{FailureRecord, ReportWriter};
+use crate::schema::{mime_matches, DrupalFileAsset};
+use crate::throttle::{AdaptiveThrottle, HostRate};
 
 #[derive(Debug, Serialize, Clone)]
 pub struct FailedDownload {
+    pub id: String,
     pub filename: String,
     pub path: String,
     pub error: String,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Destination the write failed for, when only some destinations failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+/// Per-destination write outcome for a sync
+#[derive(Debug, Serialize, Clone)]
+pub struct DestinationReport {
+    pub destination: PathBuf,
+    pub written: usize,
+    pub failed: usize,
+}
+
+/// How empty response bodies are handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ZeroBytePolicy {
+    /// Write empty files when the asset doesn't declare a non-zero size
+    Allow,
+    /// Never create empty files; assets declared as zero bytes aren't requested
+    Skip,
+    /// Treat any empty body as a failure unless the asset declares zero bytes
+    Fail,
+}
+
+/// Separator used by manifest paths
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathSeparator {
+    /// Backslashes are separators only in paths without any forward slash
+    #[default]
+    Auto,
+    /// Paths use `/`; backslashes are part of names
+    Slash,
+    /// Paths use `\`, as produced on Windows
+    Backslash,
+}
+
+impl PathSeparator {
+    /// Rewrites a manifest path to use `/` separators
+    pub fn normalize(&self, path: &str) -> String {
+        let convert = match self {
+            PathSeparator::Auto => !path.contains('/'),
+            PathSeparator::Slash => false,
+            PathSeparator::Backslash => true,
+        };
+        if convert {
+            path.replace('\\', "/")
+        } else {
+            path.to_string()
+        }
+    }
+}
+
+/// Oldest TLS version HTTP clients will negotiate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TlsVersion {
+    #[default]
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        }
+    }
+
+    /// Starts a client builder that refuses anything older than this version
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        match self {
+            TlsVersion::Tls12 => {
+                reqwest::Client::builder().min_tls_version(reqwest::tls::Version::TLS_1_2)
+            }
+            // native-tls can't require 1.3, so that minimum needs rustls
+            TlsVersion::Tls13 => reqwest::Client::builder()
+                .use_rustls_tls()
+                .min_tls_version(reqwest::tls::Version::TLS_1_3),
+        }
+    }
+
+    pub fn client(&self) -> Result<reqwest::Client> {
+        self.client_builder()
+            .build()
+            .context("Failed to build HTTP client")
+    }
+
+    /// Turns a failed request into an error that names the TLS minimum when
+    /// the handshake was refused
+    pub fn explain(&self, error: reqwest::Error) -> anyhow::Error {
+        let details = format!("{:?}", error).to_lowercase();
+        let handshake = ["handshake", "protocolversion", "protocol version"]
+            .iter()
+            .any(|needle| details.contains(needle));
+        if error.is_connect() && handshake {
+            anyhow::Error::new(error).context(format!(
+                "TLS handshake failed: the server may not support TLS {} or newer (see --min-tls-version)",
+                self.as_str()
+            ))
+        } else {
+            error.into()
+        }
+    }
+}
+
+/// Line ending normalization applied to `text/*` assets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LineEndings {
+    Lf,
+    Crlf,
+    None,
+}
+
+impl LineEndings {
+    /// Rewrites every line ending in `content`
+    pub fn normalize(&self, content: &[u8]) -> Vec<u8> {
+        let mut lf = Vec::with_capacity(content.len());
+        let mut bytes = content.iter().peekable();
+        while let Some(&byte) = bytes.next() {
+            if byte == b'\r' && bytes.peek() == Some(&&b'\n') {
+                continue;
+            }
+            lf.push(byte);
+        }
+
+        match self {
+            LineEndings::Crlf => {
+                let mut crlf = Vec::with_capacity(lf.len() + lf.len() / 32);
+                for byte in lf {
+                    if byte == b'\n' {
+                        crlf.push(b'\r');
+                    }
+                    crlf.push(byte);
+                }
+                crlf
+            }
+            _ => lf,
+        }
+    }
+}
+
+/// Allowed difference between a declared and an actual file size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeTolerance {
+    /// Absolute difference in bytes
+    Bytes(u64),
+    /// Difference as a percentage of the declared size
+    Percent(f64),
+}
+
+impl SizeTolerance {
+    /// Checks whether `actual` is within tolerance of `expected`
+    pub fn matches(&self, expected: u64, actual: u64) -> bool {
+        let difference = expected.abs_diff(actual);
+        match *self {
+            SizeTolerance::Bytes(bytes) => difference <= bytes,
+            SizeTolerance::Percent(percent) => difference as f64 <= expected as f64 * percent / 100.0,
+        }
+    }
+}
+
+impl Default for SizeTolerance {
+    fn default() -> Self {
+        SizeTolerance::Bytes(0)
+    }
+}
+
+impl std::str::FromStr for SizeTolerance {
+    type Err = String;
+
+    /// Parses `512` as bytes or `2.5%` as a percentage
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid percentage '{}'", s))?;
+            if !(0.0..=100.0).contains(&percent) {
+                return Err(format!("percentage must be between 0 and 100, got '{}'", s));
+            }
+            Ok(SizeTolerance::Percent(percent))
+        } else {
+            s.parse()
+                .map(SizeTolerance::Bytes)
+                .map_err(|_| format!("expected a byte count or a percentage, got '{}'", s))
+        }
+    }
+}
+
+/// Reads `reader` to the end in 64 KiB chunks, returning the SHA-256 and
+/// MD5 digests of everything read
+fn hash_reader(mut reader: impl std::io::Read) -> std::io::Result<(String, String)> {
+    let mut sha256 = Sha256::new();
+    let mut md5 = md5::Context::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        sha256.update(&buffer[..read]);
+        md5.consume(&buffer[..read]);
+    }
+    Ok((format!("{:x}", sha256.finalize()), format!("{:x}", md5.compute())))
+}
+
+/// Hashes a file in fixed-size reads on the blocking pool, returning
+/// its SHA-256 and MD5 digests from a single pass. With `gzip` the
+/// decompressed content is hashed as it streams through the decoder.
+pub async fn file_digests(path: &Path, gzip: bool) -> Result<(String, String)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<(String, String)> {
+        let file = std::fs::File::open(&path)
+            .context(format!("Failed to open {}", path.display()))?;
+        if gzip {
+            let decoder = async_compression::tokio::bufread::GzipDecoder::new(tokio::io::BufReader::new(
+                fs::File::from_std(file),
+            ));
+            hash_reader(tokio_util::io::SyncIoBridge::new(decoder))
+                .context(format!("Failed to decompress {}", path.display()))
+        } else {
+            hash_reader(file).context(format!("Failed to read {}", path.display()))
+        }
+    })
+    .await?
+}
+
+/// Sibling temp file a write goes to before being renamed to `path`
+pub fn part_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    path.with_file_name(format!(".{}.part", name))
+}
+
+/// Removes `.<name>.part` files left under `root` by an interrupted run,
+/// returning how many were removed
+pub async fn remove_stray_parts(root: &Path) -> Result<usize> {
+    let mut removed = 0;
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(mut entries) = fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() && name.starts_with('.') && name.ends_with(".part") {
+                fs::remove_file(entry.path())
+                    .await
+                    .context(format!("Failed to remove {}", entry.path().display()))?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Permissions given to written files unless `--file-mode` says otherwise
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// Creates `path` and any missing parents, applying `mode` on Unix to each
+/// directory this call created. Existing directories keep their mode.
+pub async fn create_dir_all_with_mode(path: &Path, mode: Option<u32>) -> std::io::Result<()> {
+    let missing: Vec<PathBuf> = path
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+        .map(Path::to_path_buf)
+        .collect();
+    fs::create_dir_all(path).await?;
+
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        for dir in &missing {
+            fs::set_permissions(dir, std::fs::Permissions::from_mode(mode)).await?;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (missing, mode);
+
+    Ok(())
+}
+
+/// Whether an asset path is already a full http(s) URL
+fn is_absolute_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// One hashing job per available core
+pub fn default_hash_concurrency() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
 }
 
 #[derive(Debug, Clone)]
@@ -26,10 +332,123 @@ pub struct DownloadConfig {
     pub max_concurrent: usize,
     pub download_delay: u64,      // milliseconds
     pub download_timeout: u64,    // seconds
+    pub connect_timeout: u64,     // seconds
     pub max_retries: usize,
+    /// Download files even when a matching local copy exists
+    pub force: bool,
+    /// Classify assets from local state only, without requesting or writing anything
+    pub dry_run: bool,
     pub base_url: Option<String>,
     pub username: Option<String>,
     pub password: Option<String>,
+    /// Bearer token, used when no username/password pair is set
+    pub token: Option<String>,
+    /// Resolver URL template (`{id}`, `{filename}`, `{path}`, `{uri}`) that
+    /// returns the real download URL
+    pub resolve_endpoint: Option<String>,
+    /// JSON body field holding the resolved URL when no Location header is sent
+    pub resolve_field: String,
+    /// Longest filename written locally, in bytes
+    pub max_filename_length: usize,
+    pub zero_byte: ZeroBytePolicy,
+    /// Allowed difference between declared and actual sizes
+    pub size_tolerance: SizeTolerance,
+    /// Verify each file right after it's written, re-fetching on mismatch
+    pub verify_inline: bool,
+    /// Line endings applied to `text/*` assets before writing
+    pub line_endings: LineEndings,
+    /// Concurrency caps per lowercase file extension, below `max_concurrent`
+    pub ext_concurrency: HashMap<String, usize>,
+    /// Content-addressed store; when set, file bytes live here once and
+    /// destinations get symlinks
+    pub cas_dir: Option<PathBuf>,
+    /// Seconds over which concurrency grows from 1 to `max_concurrent`
+    pub ramp_up: u64,
+    /// Skip files already present with the declared size when a HEAD
+    /// request shows the remote copy is unchanged
+    pub verify_remote_unchanged: bool,
+    /// Oldest TLS version accepted from servers
+    pub min_tls_version: TlsVersion,
+    /// ETag/Last-Modified cache used for conditional requests
+    pub etag_cache: Option<EtagCache>,
+    /// Hashing jobs allowed to run at once on the blocking pool
+    pub hash_concurrency: usize,
+    /// How backslashes in manifest paths are treated
+    pub path_separator: PathSeparator,
+    /// Base URLs tried in order when a download from `base_url` fails
+    pub mirror_urls: Vec<String>,
+    /// MIME patterns of assets stored gzip-compressed as `<name>.gz`
+    pub store_compressed: Vec<String>,
+    /// Abort the run when this many downloads are rejected with 401/403
+    /// before any succeeds
+    pub auth_failure_limit: Option<usize>,
+    /// Progress bars updated as downloads stream in
+    pub progress: Option<SyncProgress>,
+    /// Combined download rate cap in bytes per second
+    pub max_bandwidth: Option<u64>,
+    /// Continue partial downloads left in `.part` files with Range requests
+    pub resume: bool,
+    /// Unix permissions of written files
+    pub file_mode: u32,
+    /// Unix permissions of directories created during the sync
+    pub dir_mode: Option<u32>,
+}
+
+impl DownloadConfig {
+    /// Client builder with the TLS minimum and the connect timeout applied.
+    /// Downloads have no overall deadline, since a large or throttled body
+    /// may take far longer than --download-timeout; instead each wait for
+    /// data is bounded by `idle_timeout`.
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        self.min_tls_version
+            .client_builder()
+            .connect_timeout(Duration::from_secs(self.connect_timeout))
+    }
+
+    /// Longest wait for a response or the next chunk of its body
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.download_timeout)
+    }
+
+    pub fn client(&self) -> Result<reqwest::Client> {
+        self.client_builder()
+            .build()
+            .context("Failed to build HTTP client")
+    }
+
+    /// Turns a failed request into an error naming the timeout that expired,
+    /// or the TLS minimum when the handshake was refused
+    pub fn explain(&self, url: &str, error: reqwest::Error) -> anyhow::Error {
+        if !error.is_timeout() {
+            return self.min_tls_version.explain(error);
+        }
+        let message = if error.is_connect() {
+            format!("Connecting to {} timed out after {}s (see --connect-timeout)", url, self.connect_timeout)
+        } else {
+            format!("Request to {} timed out after {}s (see --download-timeout)", url, self.download_timeout)
+        };
+        anyhow::Error::new(error).context(message)
+    }
+
+    /// Whether the asset's content is rewritten before it's stored, so it no
+    /// longer matches the declared checksum
+    pub fn transforms(&self, asset: &DrupalFileAsset) -> bool {
+        self.line_endings != LineEndings::None && asset.mime.starts_with("text/")
+    }
+
+    /// Whether a local copy of `size` bytes matches the asset's declared size.
+    /// Rewritten content never does, so only its presence counts.
+    pub fn matches_declared_size(&self, asset: &DrupalFileAsset, size: u64) -> bool {
+        self.transforms(asset)
+            || asset.size.map_or(true, |expected| self.size_tolerance.matches(expected, size))
+    }
+
+    /// Whether the asset is written gzip-compressed
+    pub fn compresses(&self, asset: &DrupalFileAsset) -> bool {
+        self.store_compressed
+            .iter()
+            .any(|pattern| mime_matches(pattern, &asset.mime))
+    }
 }
 
 impl Default for DownloadConfig {
@@ -38,129 +457,2165 @@ impl Default for DownloadConfig {
             max_concurrent: 4,      // 4 concurrent downloads
             download_delay: 100,    // 100ms delay between downloads
             download_timeout: 30,    // 30 seconds timeout
+            connect_timeout: 10,     // 10 seconds to establish a connection
             max_retries: 3,         // 3 retries for failed downloads
+            force: false,
+            dry_run: false,
             base_url: None,
             username: None,
             password: None,
+            token: None,
+            resolve_endpoint: None,
+            resolve_field: "url".to_string(),
+            max_filename_length: crate::schema::DEFAULT_MAX_FILENAME_LENGTH,
+            zero_byte: ZeroBytePolicy::Allow,
+            size_tolerance: SizeTolerance::default(),
+            verify_inline: false,
+            line_endings: LineEndings::None,
+            ext_concurrency: HashMap::new(),
+            cas_dir: None,
+            ramp_up: 0,
+            verify_remote_unchanged: false,
+            min_tls_version: TlsVersion::default(),
+            etag_cache: None,
+            hash_concurrency: default_hash_concurrency(),
+            path_separator: PathSeparator::default(),
+            mirror_urls: Vec::new(),
+            store_compressed: Vec::new(),
+            auth_failure_limit: Some(AUTH_FAILURE_LIMIT),
+            progress: None,
+            max_bandwidth: None,
+            resume: false,
+            file_mode: DEFAULT_FILE_MODE,
+            dir_mode: None,
+        }
+    }
+}
+
+/// Content fingerprint of a fetched asset
+#[derive(Debug, Serialize, Clone)]
+pub struct FileHash {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Recorded in sync logs as the file's fingerprint
+    pub md5: String,
+}
+
+/// Result of fetching an asset once and writing it to each destination
+struct FetchOutcome {
+    /// URL the content was fetched from
+    url: String,
+    hash: FileHash,
+    writes: Vec<Result<()>>,
+    /// ETag/Last-Modified of the response, cached once every write succeeds
+    validators: CacheEntry,
+    /// Whether the written content differs from the fetched bytes, making
+    /// the declared size meaningless for checks
+    transformed: bool,
+}
+
+/// Resolves download URLs through an indirection endpoint such as a
+/// signed-URL broker, caching each resolution for the run
+#[derive(Clone)]
+struct UrlResolver {
+    client: reqwest::Client,
+    template: String,
+    field: String,
+    cache: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl UrlResolver {
+    fn new(template: String, field: String, config: &DownloadConfig) -> Result<Self> {
+        // Redirects are read rather than followed so the Location header is visible
+        let client = config
+            .client_builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            client,
+            template,
+            field,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    fn expand(&self, asset: &DrupalFileAsset) -> String {
+        self.template
+            .replace("{id}", &asset.id)
+            .replace("{filename}", &asset.filename)
+            .replace("{path}", asset.path.trim_start_matches('/'))
+            .replace("{uri}", &asset.uri)
+    }
+
+    async fn resolve(&self, asset: &DrupalFileAsset, config: &DownloadConfig) -> Result<String> {
+        let resolver_url = self.expand(asset);
+        if let Some(url) = self.cache.lock().await.get(&resolver_url) {
+            return Ok(url.clone());
+        }
+
+        let mut request = self.client.get(&resolver_url).timeout(config.idle_timeout());
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            request = request.basic_auth(username, Some(password));
+        } else if let Some(token) = &config.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| config.explain(&resolver_url, e))
+            .context(format!("Failed to reach resolver {}", resolver_url))?;
+
+        let location = if let Some(location) = response.headers().get(reqwest::header::LOCATION) {
+            location.to_str()?.to_string()
+        } else if response.status().is_success() {
+            let body: serde_json::Value = response.json().await?;
+            body.get(&self.field)
+                .and_then(|value| value.as_str())
+                .ok_or_else(|| anyhow::anyhow!("Resolver response has no '{}' field", self.field))?
+                .to_string()
+        } else {
+            return Err(anyhow::anyhow!(
+                "Resolver {} returned status {}",
+                resolver_url,
+                response.status()
+            ));
+        };
+
+        // Relative targets resolve against the resolver URL
+        let url = url::Url::parse(&resolver_url)?.join(&location)?.to_string();
+        self.cache.lock().await.insert(resolver_url, url.clone());
+        Ok(url)
+    }
+}
+
+/// Per-file result yielded by `Downloader::download_files_stream`
+#[derive(Debug, Clone)]
+pub enum DownloadOutcome {
+    /// Fetched and written to at least one destination
+    Downloaded {
+        asset: DrupalFileAsset,
+        bytes: u64,
+        elapsed: Duration,
+    },
+    /// Skipped without writing anything
+    Skipped {
+        asset: DrupalFileAsset,
+        elapsed: Duration,
+    },
+    /// Failed to fetch or to write to every destination
+    Failed {
+        asset: DrupalFileAsset,
+        error: String,
+        elapsed: Duration,
+    },
+}
+
+impl DownloadOutcome {
+    /// The outcome as a `--log-format jsonl` event
+    pub fn event(&self) -> serde_json::Value {
+        match self {
+            DownloadOutcome::Downloaded { asset, bytes, elapsed } => serde_json::json!({
+                "event": "downloaded",
+                "id": asset.id,
+                "file": asset.filename,
+                "bytes": bytes,
+                "ms": elapsed.as_millis() as u64,
+            }),
+            DownloadOutcome::Skipped { asset, elapsed } => serde_json::json!({
+                "event": "skipped",
+                "id": asset.id,
+                "file": asset.filename,
+                "ms": elapsed.as_millis() as u64,
+            }),
+            DownloadOutcome::Failed { asset, error, elapsed } => serde_json::json!({
+                "event": "failed",
+                "id": asset.id,
+                "file": asset.filename,
+                "error": error,
+                "ms": elapsed.as_millis() as u64,
+            }),
+        }
+    }
+}
+
+/// What a sync would do with one asset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeAction {
+    Add,
+    Update,
+    Unchanged,
+}
+
+/// One entry of a `Downloader::plan_changes` change plan
+#[derive(Debug, Clone)]
+pub struct PlannedChange {
+    pub asset: DrupalFileAsset,
+    pub action: ChangeAction,
+    /// Remote size from the HEAD response or, failing that, the metadata
+    pub remote_size: Option<u64>,
+    pub local_size: Option<u64>,
+    /// Why the asset was classified this way
+    pub reason: String,
+}
+
+/// HEAD response for one asset compared with its metadata
+#[derive(Debug, Serialize, Clone)]
+pub struct PrecheckResult {
+    pub id: String,
+    pub filename: String,
+    pub url: String,
+    /// HTTP status, or none when the request itself failed
+    pub status: Option<u16>,
+    pub expected_size: Option<u64>,
+    /// Content-Length reported by the server
+    pub content_length: Option<u64>,
+    pub expected_mime: String,
+    /// Content-Type reported by the server, without parameters
+    pub content_type: Option<String>,
+    pub ok: bool,
+    /// Everything wrong with the asset, separated by "; "
+    pub problems: String,
+}
+
+/// Downloads that must all fail with 401/403 before the run is aborted
+pub const AUTH_FAILURE_LIMIT: usize = 5;
+
+/// A download answered with a non-success status
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub url: String,
+    pub status: reqwest::StatusCode,
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to download file: {} (status: {})", self.url, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// A body whose length doesn't match the asset's declared size, usually a
+/// truncated response
+#[derive(Debug)]
+pub struct SizeMismatchError {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl std::fmt::Display for SizeMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "size mismatch: expected {} got {}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for SizeMismatchError {}
+
+/// A download that sent nothing for longer than --download-timeout
+#[derive(Debug)]
+pub struct IdleTimeoutError {
+    pub url: String,
+    pub seconds: u64,
+}
+
+impl std::fmt::Display for IdleTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Download of {} stalled: no data for {}s (see --download-timeout)",
+            self.url, self.seconds
+        )
+    }
+}
+
+impl std::error::Error for IdleTimeoutError {}
+
+/// A body whose SHA-256 doesn't match the asset's declared checksum
+#[derive(Debug)]
+pub struct ChecksumMismatchError {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for ChecksumMismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sha256 mismatch: expected {} got {}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for ChecksumMismatchError {}
+
+impl HttpStatusError {
+    /// Server errors, timeouts and rate limiting may clear up on a retry
+    fn is_retryable(&self) -> bool {
+        self.status.is_server_error()
+            || self.status == reqwest::StatusCode::REQUEST_TIMEOUT
+            || self.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+    }
+}
+
+/// First delay between download attempts, doubled after each retry
+const RETRY_BACKOFF_MS: u64 = 500;
+
+/// Whether a failed download is worth another attempt: network errors,
+/// truncated or corrupted bodies and retryable statuses are, other 4xx
+/// responses and local errors are not
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<SizeMismatchError>() || cause.is::<ChecksumMismatchError>() || cause.is::<IdleTimeoutError>() {
+            true
+        } else if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            e.is_retryable()
+        } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            !e.is_builder()
+        } else {
+            false
+        }
+    })
+}
+
+/// Watches the first downloads of a run for credentials being rejected
+#[derive(Default)]
+struct AuthGuard {
+    successes: AtomicUsize,
+    rejections: AtomicUsize,
+    tripped: AtomicBool,
+}
+
+impl AuthGuard {
+    /// Records a finished download; trips once `limit` downloads were
+    /// rejected with 401/403 before any succeeded
+    fn record(&self, result: &Result<Option<FetchOutcome>>, limit: usize) {
+        let rejected = match result {
+            Err(e) => e
+                .downcast_ref::<HttpStatusError>()
+                .map(|e| {
+                    e.status == reqwest::StatusCode::UNAUTHORIZED || e.status == reqwest::StatusCode::FORBIDDEN
+                })
+                .unwrap_or(false),
+            Ok(_) => false,
+        };
+        if !rejected {
+            if result.is_ok() {
+                self.successes.fetch_add(1, Ordering::SeqCst);
+            }
+            return;
+        }
+        let rejections = self.rejections.fetch_add(1, Ordering::SeqCst) + 1;
+        if rejections >= limit && self.successes.load(Ordering::SeqCst) == 0 {
+            self.tripped.store(true, Ordering::SeqCst);
         }
     }
+
+    fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::SeqCst)
+    }
+}
+
+/// Which written files the sampled verification pass re-hashes
+#[derive(Debug, Clone)]
+pub struct VerifySample {
+    /// Percentage of the remaining files picked at random
+    pub percent: f64,
+    /// The largest files are always hashed
+    pub largest: usize,
+    pub seed: u64,
+}
+
+/// How much of the written data the sampled verification covered
+#[derive(Debug, Default)]
+pub struct SampleCoverage {
+    pub total_files: usize,
+    pub total_bytes: u64,
+    pub hashed_files: usize,
+    pub hashed_bytes: u64,
+    pub failures: usize,
+}
+
+/// Small seeded generator (SplitMix64) for reproducible sampling
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// Uniform value in [0, 100)
+    fn next_percent(&mut self) -> f64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64 * 100.0
+    }
+}
+
+/// A file written gzip-compressed by `--store-compressed`
+#[derive(Debug, Serialize, Clone)]
+pub struct CompressedFile {
+    pub path: PathBuf,
+    pub original_size: u64,
+    pub compressed_size: u64,
 }
 
+/// Result of comparing a local file with the remote copy's metadata
+struct RemoteCheck {
+    remote_size: Option<u64>,
+    /// Why the remote copy is considered different, if it is
+    change: Option<String>,
+}
+
+#[derive(Clone)]
 pub struct Downloader {
     config: DownloadConfig,
     failed_downloads: Arc<Mutex<Vec<FailedDownload>>>,
+    destination_reports: Arc<Mutex<Vec<DestinationReport>>>,
+    written_files: Arc<Mutex<Vec<(DrupalFileAsset, PathBuf)>>>,
+    file_hashes: Arc<Mutex<Vec<FileHash>>>,
+    skipped_files: Arc<Mutex<Vec<DrupalFileAsset>>>,
+    /// What a dry run would have done with each asset
+    planned_changes: Arc<Mutex<Vec<PlannedChange>>>,
+    /// URL each fetched asset was downloaded from, keyed by asset id
+    sources: Arc<Mutex<HashMap<String, String>>>,
+    auth_guard: Arc<AuthGuard>,
+    throttle: AdaptiveThrottle,
+    hash_limit: Arc<Semaphore>,
 }
 
 impl Downloader {
     pub fn new(config: DownloadConfig) -> Self {
         Self {
+            throttle: AdaptiveThrottle::new(config.max_concurrent).with_bandwidth(config.max_bandwidth),
+            hash_limit: Arc::new(Semaphore::new(config.hash_concurrency.max(1))),
             config,
             failed_downloads: Arc::new(Mutex::new(Vec::new())),
+            destination_reports: Arc::new(Mutex::new(Vec::new())),
+            written_files: Arc::new(Mutex::new(Vec::new())),
+            file_hashes: Arc::new(Mutex::new(Vec::new())),
+            skipped_files: Arc::new(Mutex::new(Vec::new())),
+            planned_changes: Arc::new(Mutex::new(Vec::new())),
+            sources: Arc::new(Mutex::new(HashMap::new())),
+            auth_guard: Arc::new(AuthGuard::default()),
         }
     }
 
-    pub async fn download_files(&self, assets: &[DrupalFileAsset], destination: PathBuf) -> Result<()> {
-        let client = reqwest::Client::new();
-        let config = self.config.clone();
-        let max_concurrent = config.max_concurrent;
+    /// Returns the original and on-disk size of every file written compressed
+    pub async fn compressed_files(&self) -> Vec<CompressedFile> {
+        let written = self.written_files.lock().await.clone();
+        let mut files = Vec::new();
+        for (asset, destination) in written {
+            if !self.config.compresses(&asset) {
+                continue;
+            }
+            let path = Self::local_file_path(&asset, &self.config, &destination);
+            if let (Ok(metadata), Ok(original_size)) =
+                (fs::metadata(&path).await, Self::gzip_original_size(&path, asset.size).await)
+            {
+                files.push(CompressedFile {
+                    path,
+                    original_size,
+                    compressed_size: metadata.len(),
+                });
+            }
+        }
+        files
+    }
 
-        let mut handles = Vec::new();
+    /// Returns the URL each asset was fetched from, which may be a mirror
+    pub async fn download_sources(&self) -> HashMap<String, String> {
+        self.sources.lock().await.clone()
+    }
 
-        // Clone all assets first to avoid lifetime issues
-        let assets: Vec<DrupalFileAsset> = assets.to_vec();
+    /// Returns the request rate adapted for each host contacted
+    pub async fn host_rates(&self) -> Vec<HostRate> {
+        self.throttle.host_rates().await
+    }
 
-        for asset in assets {
-            let client = client.clone();
-            let config = config.clone();
-            let destination = destination.clone();
-            let failed_downloads = self.failed_downloads.clone();
+    /// Returns every asset skipped without writing in the last run
+    pub async fn skipped_files(&self) -> Vec<DrupalFileAsset> {
+        self.skipped_files.lock().await.clone()
+    }
 
-            let handle = tokio::spawn(async move {
-                if let Err(e) = Self::download_single_file(&asset, &client, &config, &destination).await {
-                    let failed = FailedDownload {
-                        filename: asset.filename.clone(),
-                        path: asset.path.clone(),
-                        error: e.to_string(),
-                        timestamp: chrono::Utc::now(),
-                    };
-                    failed_downloads.lock().await.push(failed);
-                }
-                sleep(Duration::from_millis(config.download_delay)).await;
-            });
+    /// Returns the change plan recorded by a dry run
+    pub async fn planned_changes(&self) -> Vec<PlannedChange> {
+        self.planned_changes.lock().await.clone()
+    }
 
-            handles.push(handle);
+    /// Returns every (asset, destination) pair written in the last run
+    pub async fn written_files(&self) -> Vec<(DrupalFileAsset, PathBuf)> {
+        self.written_files.lock().await.clone()
+    }
 
-            if handles.len() >= max_concurrent {
-                for handle in handles.drain(..) {
-                    handle.await?;
-                }
-            }
-        }
+    /// Returns every failure recorded in the last run
+    pub async fn failed_downloads(&self) -> Vec<FailedDownload> {
+        self.failed_downloads.lock().await.clone()
+    }
 
-        for handle in handles {
-            handle.await?;
+    /// Writes the last run's failures to `<path>.csv` and `<path>.json`,
+    /// returning how many there were
+    pub async fn write_failures(&self, path: &Path, sync_id: &str, config_id: &str) -> Result<usize> {
+        let records: Vec<FailureRecord> = self
+            .failed_downloads
+            .lock()
+            .await
+            .iter()
+            .map(|failed| FailureRecord::from_failed_download(failed, sync_id, config_id))
+            .collect();
+        if let Some(parent) = path.parent() {
+            create_dir_all_with_mode(parent, self.config.dir_mode).await?;
         }
+        // The report covers this run only, so don't append to the last one
+        for extension in ["csv", "json"] {
+            let _ = fs::remove_file(path.with_extension(extension)).await;
+        }
+        ReportWriter::new(path.to_path_buf(), "failures")
+            .write_failure_records(&records)
+            .await
+            .context(format!("Failed to write failure report {}", path.display()))?;
+        Ok(records.len())
+    }
 
-        Ok(())
+    /// Returns the content hash of every file fetched in the last run
+    pub async fn file_hashes(&self) -> Vec<FileHash> {
+        self.file_hashes.lock().await.clone()
     }
 
-    fn get_download_url(asset: &DrupalFileAsset, config: &DownloadConfig) -> Result<String> {
-        let base_url = config.base_url.as_ref().ok_or_else(|| {
-            anyhow::anyhow!("Base URL is required for downloading assets")
-        })?;
+    /// Returns the write outcome for each destination of the last run
+    pub async fn destination_reports(&self) -> Vec<DestinationReport> {
+        self.destination_reports.lock().await.clone()
+    }
 
-        let base = base_url.trim_end_matches('/');
-        let path = asset.path.trim_start_matches('/');
-        let url = format!("{}/{}", base, path);
-        
-        Ok(url)
+    /// Downloads each asset once and writes it to every destination
+    pub async fn download_files(&self, assets: &[DrupalFileAsset], destinations: Vec<PathBuf>) -> Result<()> {
+        let mut outcomes = self.download_files_stream(assets, destinations).await?;
+        while let Some(outcome) = outcomes.next().await {
+            if crate::output::json_lines() {
+                crate::output::print_event(&outcome.event());
+            }
+        }
+        if self.auth_guard.tripped() {
+            return Err(anyhow::anyhow!(
+                "Authentication is failing for all requests - check credentials (the first {} downloads were rejected with 401/403)",
+                self.config.auth_failure_limit.unwrap_or_default()
+            ));
+        }
+        Ok(())
     }
 
-    async fn download_single_file(
-        asset: &DrupalFileAsset,
-        client: &reqwest::Client,
-        config: &DownloadConfig,
-        destination: &PathBuf,
-    ) -> Result<()> {
-        let url = Self::get_download_url(asset, config)?;
-        let dest_path = destination.join(&asset.filename);
+    /// Starts downloading and yields each file's outcome as it finishes.
+    ///
+    /// The same results are recorded as with `download_files`, so the
+    /// accessors reflect the run once the stream is exhausted.
+    pub async fn download_files_stream(
+        &self,
+        assets: &[DrupalFileAsset],
+        destinations: Vec<PathBuf>,
+    ) -> Result<impl futures::Stream<Item = DownloadOutcome> + Unpin> {
+        let resolver = match &self.config.resolve_endpoint {
+            Some(template) => Some(UrlResolver::new(
+                template.clone(),
+                self.config.resolve_field.clone(),
+                &self.config,
+            )?),
+            None => None,
+        };
 
-        if let Some(parent) = dest_path.parent() {
-            fs::create_dir_all(parent).await?;
-        }
+        *self.destination_reports.lock().await = destinations
+            .iter()
+            .map(|destination| DestinationReport {
+                destination: destination.clone(),
+                written: 0,
+                failed: 0,
+            })
+            .collect();
 
-        let mut request = client.get(&url);
+        let client = self.config.client()?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let downloader = self.clone();
+        // Clone all assets first to avoid lifetime issues
+        let mut assets: Vec<DrupalFileAsset> = assets.to_vec();
+        // Stable, so manifest order is kept within a priority
+        assets.sort_by_key(|asset| std::cmp::Reverse(asset.priority()));
+        tokio::spawn(async move {
+            downloader.run(client, assets, destinations, resolver, tx).await;
+        });
 
-        if let (Some(username), Some(password)) = (&config.username, &config.password) {
-            request = request.header(
-                AUTHORIZATION,
-                format!("Basic {}", base64_engine.encode(format!("{}:{}", username, password)))
-            );
-        }
+        Ok(Box::pin(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|outcome| (outcome, rx))
+        })))
+    }
 
-        let response = request.send().await?;
+    /// Drives the download tasks, sending each outcome to `tx`
+    async fn run(
+        self,
+        client: reqwest::Client,
+        assets: Vec<DrupalFileAsset>,
+        destinations: Vec<PathBuf>,
+        resolver: Option<UrlResolver>,
+        tx: tokio::sync::mpsc::UnboundedSender<DownloadOutcome>,
+    ) {
+        let max_concurrent = self.config.max_concurrent;
+        let ext_limits: HashMap<String, Arc<Semaphore>> = self
+            .config
+            .ext_concurrency
+            .iter()
+            .map(|(ext, cap)| (ext.clone(), Arc::new(Semaphore::new((*cap).max(1)))))
+            .collect();
 
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Failed to download file: {} (status: {})",
-                url,
-                response.status()
-            ));
+        if let Some(progress) = &self.config.progress {
+            progress.set_total(assets.len());
         }
 
-        let content = response.bytes().await?;
-        fs::write(&dest_path, content).await?;
+        let (ramp, ramp_task) = if self.config.ramp_up > 0 && max_concurrent > 1 {
+            let ramp = Arc::new(Semaphore::new(1));
+            let task = tokio::spawn(Self::ramp_up(ramp.clone(), max_concurrent, self.config.ramp_up));
+            (Some(ramp), Some(task))
+        } else {
+            (None, None)
+        };
 
-        // Set file permissions to be readable and writable by the owner
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let metadata = fs::metadata(&dest_path).await?;
-            let mut perms = metadata.permissions();
-            perms.set_mode(0o644); // rw-r--r--
-            fs::set_permissions(&dest_path, perms).await?;
-        }
+        // A download starts as soon as any slot frees up rather than
+        // waiting for a whole batch to finish
+        let slots = Arc::new(Semaphore::new(max_concurrent.max(1)));
+        let mut handles = Vec::new();
 
-        Ok(())
+        for asset in assets {
+            let Ok(slot) = slots.clone().acquire_owned().await else {
+                break;
+            };
+            if self.auth_guard.tripped() {
+                break;
+            }
+            let client = client.clone();
+            let destinations = destinations.clone();
+            let resolver = resolver.clone();
+            let downloader = self.clone();
+            let tx = tx.clone();
+            let ext_limit = asset
+                .get_extension()
+                .and_then(|ext| ext_limits.get(&ext).cloned());
+            let ramp = ramp.clone();
+
+            let handle = tokio::spawn(async move {
+                let ramp_permit = match ramp {
+                    Some(ramp) => ramp.acquire_owned().await.ok(),
+                    None => None,
+                };
+                let permit = match ext_limit {
+                    Some(limit) => limit.acquire_owned().await.ok(),
+                    None => None,
+                };
+                // Downloads queued behind the limits are dropped once credentials are known bad
+                if downloader.auth_guard.tripped() {
+                    return;
+                }
+                let outcome = downloader
+                    .process_asset(asset, &client, resolver.as_ref(), &destinations)
+                    .await;
+                if let Some(progress) = &downloader.config.progress {
+                    progress.file_done();
+                }
+                drop(permit);
+                drop(ramp_permit);
+                // The receiver may have been dropped; results are still recorded
+                let _ = tx.send(outcome);
+                // The outcome is already reported; the slot rests before its next download
+                if !downloader.config.dry_run {
+                    sleep(Duration::from_millis(downloader.config.download_delay)).await;
+                }
+                drop(slot);
+            });
+
+            handles.push(handle);
+        }
+
+        futures::future::join_all(handles).await;
+        if let Some(progress) = &self.config.progress {
+            progress.finish();
+        }
+
+        if let Some(task) = ramp_task {
+            task.abort();
+        }
+    }
+
+    /// Adds one permit at a time to `ramp` until `max_concurrent` downloads
+    /// may run, spreading the additions evenly over `seconds`
+    async fn ramp_up(ramp: Arc<Semaphore>, max_concurrent: usize, seconds: u64) {
+        let step = Duration::from_secs(seconds) / (max_concurrent as u32 - 1);
+        for permits in 2..=max_concurrent {
+            sleep(step).await;
+            ramp.add_permits(1);
+            if permits == max_concurrent {
+                status!("Reached full concurrency of {} after {}s ramp-up", max_concurrent, seconds);
+            }
+        }
+    }
+
+    /// Downloads one asset and records the result in the shared run state
+    async fn process_asset(
+        &self,
+        asset: DrupalFileAsset,
+        client: &reqwest::Client,
+        resolver: Option<&UrlResolver>,
+        destinations: &[PathBuf],
+    ) -> DownloadOutcome {
+        let started = std::time::Instant::now();
+
+        let mut asset = asset;
+        let mut attempt = 0;
+        let result = loop {
+            let mut result = Self::download_single_file(
+                &asset,
+                client,
+                &self.config,
+                resolver,
+                &self.throttle,
+                &self.hash_limit,
+                destinations,
+            )
+            .await;
+            if let Ok(Some(outcome)) = &result {
+                // Checks apply to what was written, not to the declared bytes
+                if outcome.transformed {
+                    asset.size = Some(outcome.hash.size);
+                }
+            }
+            if self.config.verify_inline {
+                if let Ok(Some(outcome)) = &mut result {
+                    if self.verify_writes(&asset, destinations, outcome).await && attempt < self.config.max_retries {
+                        attempt += 1;
+                        continue;
+                    }
+                }
+            }
+            break result;
+        };
+
+        if let Some(limit) = self.config.auth_failure_limit {
+            self.auth_guard.record(&result, limit);
+        }
+
+        match result {
+            Ok(None) => {
+                if self.config.dry_run {
+                    let change = Self::plan_local(&asset, &self.config, destinations).await;
+                    self.planned_changes.lock().await.push(change);
+                }
+                self.skipped_files.lock().await.push(asset.clone());
+                DownloadOutcome::Skipped {
+                    asset,
+                    elapsed: started.elapsed(),
+                }
+            }
+            Ok(Some(outcome)) => {
+                let bytes = outcome.hash.size;
+                self.sources.lock().await.insert(asset.id.clone(), outcome.url.clone());
+                self.file_hashes.lock().await.push(outcome.hash);
+                let mut errors = Vec::new();
+                let mut reports = self.destination_reports.lock().await;
+                for (index, outcome) in outcome.writes.into_iter().enumerate() {
+                    match outcome {
+                        Ok(()) => {
+                            reports[index].written += 1;
+                            self.written_files
+                                .lock()
+                                .await
+                                .push((asset.clone(), destinations[index].clone()));
+                        }
+                        Err(e) => {
+                            reports[index].failed += 1;
+                            errors.push(e.to_string());
+                            self.failed_downloads.lock().await.push(FailedDownload {
+                                id: asset.id.clone(),
+                                filename: asset.filename.clone(),
+                                path: asset.path.clone(),
+                                error: e.to_string(),
+                                timestamp: chrono::Utc::now(),
+                                destination: Some(destinations[index].display().to_string()),
+                            });
+                        }
+                    }
+                }
+
+                // A partly written asset must not answer the next run's conditional request with a 304
+                if let Some(cache) = &self.config.etag_cache {
+                    if errors.is_empty() {
+                        cache.record(&asset.id, outcome.validators).await;
+                    } else {
+                        cache.forget(&asset.id).await;
+                    }
+                }
+
+                if errors.len() == destinations.len() {
+                    DownloadOutcome::Failed {
+                        asset,
+                        error: errors.join("; "),
+                        elapsed: started.elapsed(),
+                    }
+                } else {
+                    DownloadOutcome::Downloaded {
+                        asset,
+                        bytes,
+                        elapsed: started.elapsed(),
+                    }
+                }
+            }
+            Err(e) => {
+                for report in self.destination_reports.lock().await.iter_mut() {
+                    report.failed += 1;
+                }
+                let failed = FailedDownload {
+                    id: asset.id.clone(),
+                    filename: asset.filename.clone(),
+                    path: asset.path.clone(),
+                    error: e.to_string(),
+                    timestamp: chrono::Utc::now(),
+                    destination: None,
+                };
+                self.failed_downloads.lock().await.push(failed);
+                DownloadOutcome::Failed {
+                    asset,
+                    error: e.to_string(),
+                    elapsed: started.elapsed(),
+                }
+            }
+        }
+    }
+
+    /// Verifies each successful write in place, turning mismatches into write
+    /// errors. Returns whether any write failed verification.
+    async fn verify_writes(
+        &self,
+        asset: &DrupalFileAsset,
+        destinations: &[PathBuf],
+        outcome: &mut FetchOutcome,
+    ) -> bool {
+        let mut failed = false;
+        for (write, destination) in outcome.writes.iter_mut().zip(destinations) {
+            if write.is_ok() {
+                if let Err(e) = Self::verify_file(asset, &self.config, destination).await {
+                    *write = Err(anyhow::anyhow!("Verification failed: {}", e));
+                    failed = true;
+                }
+            }
+        }
+        failed
+    }
+
+    /// Classifies each asset as add, update or unchanged against `destination`
+    /// without downloading bodies. Files present locally are compared with a
+    /// HEAD request's Content-Length and Last-Modified.
+    pub async fn plan_changes(&self, assets: &[DrupalFileAsset], destination: &Path) -> Result<Vec<PlannedChange>> {
+        let resolver = match &self.config.resolve_endpoint {
+            Some(template) => Some(UrlResolver::new(
+                template.clone(),
+                self.config.resolve_field.clone(),
+                &self.config,
+            )?),
+            None => None,
+        };
+        let client = self.config.client()?;
+
+        let plan = stream::iter(assets.iter().cloned())
+            .map(|asset| {
+                let client = client.clone();
+                let resolver = resolver.clone();
+                async move { self.plan_change(asset, &client, resolver.as_ref(), destination).await }
+            })
+            .buffered(self.config.max_concurrent.max(1))
+            .collect()
+            .await;
+
+        Ok(plan)
+    }
+
+    /// Sends a HEAD request for every asset, at most `max_concurrent` at a
+    /// time, and compares the status, Content-Length and Content-Type with
+    /// the metadata. No bodies are downloaded. Results keep the asset order.
+    pub async fn precheck(&self, assets: &[DrupalFileAsset]) -> Result<Vec<PrecheckResult>> {
+        let resolver = match &self.config.resolve_endpoint {
+            Some(template) => Some(UrlResolver::new(
+                template.clone(),
+                self.config.resolve_field.clone(),
+                &self.config,
+            )?),
+            None => None,
+        };
+        let client = self.config.client()?;
+
+        let results = stream::iter(assets)
+            .map(|asset| {
+                let client = client.clone();
+                let resolver = resolver.clone();
+                async move { Self::precheck_asset(asset, &client, &self.config, resolver.as_ref()).await }
+            })
+            .buffered(self.config.max_concurrent.max(1))
+            .collect()
+            .await;
+
+        Ok(results)
+    }
+
+    async fn precheck_asset(
+        asset: &DrupalFileAsset,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+        resolver: Option<&UrlResolver>,
+    ) -> PrecheckResult {
+        let mut result = PrecheckResult {
+            id: asset.id.clone(),
+            filename: asset.filename.clone(),
+            url: String::new(),
+            status: None,
+            expected_size: asset.size,
+            content_length: None,
+            expected_mime: asset.mime.clone(),
+            content_type: None,
+            ok: false,
+            problems: String::new(),
+        };
+
+        let url = match resolver {
+            Some(resolver) => resolver.resolve(asset, config).await,
+            None => Self::get_download_url(asset, config),
+        };
+        result.url = match url {
+            Ok(url) => url,
+            Err(e) => {
+                result.problems = format!("no URL: {:#}", e);
+                return result;
+            }
+        };
+        let response = match Self::authorize(client.head(&result.url).timeout(config.idle_timeout()), &result.url, config).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                result.problems = format!("request failed: {:#}", config.explain(&result.url, e));
+                return result;
+            }
+        };
+
+        let headers = response.headers();
+        result.status = Some(response.status().as_u16());
+        result.content_length = headers
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        result.content_type = headers
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(';').next().unwrap_or_default().trim().to_lowercase());
+
+        let mut problems = Vec::new();
+        if !response.status().is_success() {
+            problems.push(format!("status {}", response.status()));
+        } else {
+            if let (Some(expected), Some(actual)) = (asset.size, result.content_length) {
+                if !config.size_tolerance.matches(expected, actual) {
+                    problems.push(format!("size {} differs from declared {}", actual, expected));
+                }
+            }
+            if let Some(content_type) = &result.content_type {
+                if !asset.mime.is_empty() && !content_type.eq_ignore_ascii_case(&asset.mime) {
+                    problems.push(format!("type {} differs from declared {}", content_type, asset.mime));
+                }
+            }
+        }
+        result.ok = problems.is_empty();
+        result.problems = problems.join("; ");
+        result
+    }
+
+    /// Classifies an asset from its local copies alone, the way a sync
+    /// decides before sending any request
+    async fn plan_local(asset: &DrupalFileAsset, config: &DownloadConfig, destinations: &[PathBuf]) -> PlannedChange {
+        let local_size = match Self::local_metadata(asset, config, &destinations[0]).await {
+            Ok((_, size)) => Some(size),
+            Err(_) => None,
+        };
+        let (action, reason) = if config.zero_byte == ZeroBytePolicy::Skip && asset.size == Some(0) {
+            (ChangeAction::Unchanged, "empty asset, skipped".to_string())
+        } else if local_size.is_none() {
+            (ChangeAction::Add, "not present locally".to_string())
+        } else if config.force {
+            (ChangeAction::Update, "--force".to_string())
+        } else if Self::present_with_size(asset, config, destinations).await {
+            (ChangeAction::Unchanged, "present with the declared size".to_string())
+        } else {
+            (ChangeAction::Update, "missing or a different size in a destination".to_string())
+        };
+
+        PlannedChange {
+            remote_size: asset.size,
+            local_size,
+            action,
+            reason,
+            asset: asset.clone(),
+        }
+    }
+
+    async fn plan_change(
+        &self,
+        asset: DrupalFileAsset,
+        client: &reqwest::Client,
+        resolver: Option<&UrlResolver>,
+        destination: &Path,
+    ) -> PlannedChange {
+        let (local, size) = match Self::local_metadata(&asset, &self.config, destination).await {
+            Ok(local) => local,
+            Err(_) => {
+                return PlannedChange {
+                    remote_size: asset.size,
+                    local_size: None,
+                    action: ChangeAction::Add,
+                    reason: "not present locally".to_string(),
+                    asset,
+                };
+            }
+        };
+        let local_size = Some(size);
+
+        let check = match Self::compare_remote(&asset, client, &self.config, resolver, &local, size).await {
+            Ok(check) => check,
+            Err(e) => {
+                // Without remote metadata the file can't be shown unchanged
+                return PlannedChange {
+                    remote_size: asset.size,
+                    local_size,
+                    action: ChangeAction::Update,
+                    reason: format!("remote check failed: {}", e),
+                    asset,
+                };
+            }
+        };
+
+        let (action, reason) = match check.change {
+            Some(reason) => (ChangeAction::Update, reason),
+            None => (ChangeAction::Unchanged, "size and modification time match".to_string()),
+        };
+
+        PlannedChange {
+            remote_size: check.remote_size.or(asset.size),
+            local_size,
+            action,
+            reason,
+            asset,
+        }
+    }
+
+    /// Compares a local file with the remote copy's HEAD response: the ETag
+    /// against the cached one when both exist, then Content-Length, then
+    /// Last-Modified against the local mtime unless the ETag already matched
+    async fn compare_remote(
+        asset: &DrupalFileAsset,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+        resolver: Option<&UrlResolver>,
+        local: &std::fs::Metadata,
+        local_size: u64,
+    ) -> Result<RemoteCheck> {
+        let url = match resolver {
+            Some(resolver) => resolver.resolve(asset, config).await?,
+            None => Self::get_download_url(asset, config)?,
+        };
+        let response = Self::authorize(client.head(&url).timeout(config.idle_timeout()), &url, config)
+            .send()
+            .await
+            .map_err(|e| config.explain(&url, e))?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("HEAD {} returned {}", url, response.status()));
+        }
+
+        let remote_size = response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        let last_modified = response
+            .headers()
+            .get(reqwest::header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| chrono::DateTime::parse_from_rfc2822(value).ok());
+        let cached_etag = match &config.etag_cache {
+            Some(cache) => cache.get(&asset.id).await.and_then(|entry| entry.etag),
+            None => None,
+        };
+        let etag = match (cached_etag, CacheEntry::from_headers(response.headers()).etag) {
+            (Some(cached), Some(remote)) => Some((cached, remote)),
+            _ => None,
+        };
+
+        let change = match (remote_size, last_modified, etag) {
+            // Catches same-size changes on servers that send no Last-Modified
+            (_, _, Some((cached, remote))) if cached != remote => {
+                Some(format!("ETag changed: {} cached, {} remote", cached, remote))
+            }
+            // A rewritten copy differs in size from the remote by design
+            (Some(remote), _, _) if remote != local_size && !config.transforms(asset) => Some(format!(
+                "size differs: {} local, {} remote",
+                local_size,
+                remote
+            )),
+            // A matching ETag vouches for the content whatever the local mtime says
+            (_, Some(modified), None)
+                if local
+                    .modified()
+                    .map(|local| chrono::DateTime::<chrono::Utc>::from(local) < modified)
+                    .unwrap_or(false) =>
+            {
+                Some(format!("modified remotely at {}", modified))
+            }
+            _ => None,
+        };
+
+        Ok(RemoteCheck { remote_size, change })
+    }
+
+    /// Metadata of the asset's local file and the size of its content, which
+    /// for a compressed file comes from `gzip_original_size`
+    async fn local_metadata(
+        asset: &DrupalFileAsset,
+        config: &DownloadConfig,
+        destination: &Path,
+    ) -> Result<(std::fs::Metadata, u64)> {
+        let path = Self::local_file_path(asset, config, destination);
+        let metadata = fs::metadata(&path)
+            .await
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let size = if config.compresses(asset) {
+            Self::gzip_original_size(&path, asset.size).await?
+        } else {
+            metadata.len()
+        };
+        Ok((metadata, size))
+    }
+
+    /// Reads the uncompressed size from a gzip file's trailer. The trailer
+    /// only holds it modulo 4 GiB, so when the declared size is that large
+    /// the file is decompressed and counted instead.
+    pub async fn gzip_original_size(path: &Path, declared: Option<u64>) -> Result<u64> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        let mut file = fs::File::open(path)
+            .await
+            .context(format!("Failed to open {}", path.display()))?;
+        if declared.is_some_and(|size| size >= 1 << 32) {
+            let mut decoder =
+                async_compression::tokio::bufread::GzipDecoder::new(tokio::io::BufReader::new(file));
+            return tokio::io::copy(&mut decoder, &mut tokio::io::sink())
+                .await
+                .context(format!("Failed to decompress {}", path.display()));
+        }
+        let mut trailer = [0u8; 4];
+        file.seek(std::io::SeekFrom::End(-4))
+            .await
+            .context(format!("{} is not a gzip file", path.display()))?;
+        file.read_exact(&mut trailer).await?;
+        Ok(u32::from_le_bytes(trailer) as u64)
+    }
+
+    /// Whether every destination already holds a copy of the asset matching
+    /// its declared size, when the manifest gives one and the content isn't
+    /// rewritten
+    async fn present_with_size(asset: &DrupalFileAsset, config: &DownloadConfig, destinations: &[PathBuf]) -> bool {
+        for destination in destinations {
+            match Self::local_metadata(asset, config, destination).await {
+                Ok((_, size)) if config.matches_declared_size(asset, size) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Checks whether an asset can be skipped because every destination
+    /// already holds a copy matching its declared size and the remote copy
+    /// is unchanged according to a HEAD request
+    async fn remote_unchanged(
+        asset: &DrupalFileAsset,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+        resolver: Option<&UrlResolver>,
+        destinations: &[PathBuf],
+    ) -> bool {
+        let mut first = None;
+        for destination in destinations {
+            let (local, size) = match Self::local_metadata(asset, config, destination).await {
+                Ok(local) => local,
+                Err(_) => return false,
+            };
+            if !config.matches_declared_size(asset, size) {
+                return false;
+            }
+            first.get_or_insert((local, size));
+        }
+
+        match first {
+            Some((local, size)) => matches!(
+                Self::compare_remote(asset, client, config, resolver, &local, size).await,
+                Ok(RemoteCheck { change: None, .. })
+            ),
+            None => false,
+        }
+    }
+
+    /// Verifies every file written by the last run, recording any that don't
+    /// match their metadata as failures. With `hash`, files whose asset
+    /// declares a sha256 are re-hashed too. Returns the number that failed.
+    pub async fn verify_written_files(&self, hash: bool) -> usize {
+        let written = self.written_files.lock().await.clone();
+
+        let results: Vec<_> = stream::iter(written)
+            .map(|(asset, destination)| async move {
+                let mut result = Self::verify_file(&asset, &self.config, &destination).await;
+                if let (Ok(()), Some(expected)) = (&result, &asset.sha256) {
+                    if hash && !self.config.transforms(&asset) {
+                        let expected = expected.trim().to_lowercase();
+                        result = self.check_file_hash(&asset, &destination, &expected).await;
+                    }
+                }
+                (asset, destination, result)
+            })
+            .buffer_unordered(self.config.max_concurrent.max(1))
+            .collect()
+            .await;
+
+        let mut failures = 0;
+        for (asset, destination, result) in results {
+            if let Err(e) = result {
+                failures += 1;
+                self.record_verify_failure(&asset, &destination, e).await;
+            }
+        }
+
+        failures
+    }
+
+    /// Re-hashes a sample of the files written by the last run and compares
+    /// them with the hash of the downloaded content. The largest files are
+    /// always included; the rest are picked at random with the given seed.
+    pub async fn verify_sampled_hashes(&self, sample: &VerifySample) -> SampleCoverage {
+        let hashes: HashMap<String, FileHash> = self
+            .file_hashes
+            .lock()
+            .await
+            .iter()
+            .map(|hash| (hash.path.clone(), hash.clone()))
+            .collect();
+        let mut candidates: Vec<(DrupalFileAsset, PathBuf, FileHash)> = self
+            .written_files
+            .lock()
+            .await
+            .iter()
+            .filter_map(|(asset, destination)| {
+                let key = if asset.path.is_empty() { &asset.filename } else { &asset.path };
+                hashes
+                    .get(key)
+                    .map(|hash| (asset.clone(), destination.clone(), hash.clone()))
+            })
+            .collect();
+        // Largest first, so the always-hashed files lead and picks are reproducible
+        candidates.sort_by(|a, b| {
+            b.2.size
+                .cmp(&a.2.size)
+                .then_with(|| a.0.id.cmp(&b.0.id))
+                .then_with(|| a.1.cmp(&b.1))
+        });
+
+        let mut rng = SampleRng::new(sample.seed);
+        let mut coverage = SampleCoverage {
+            total_files: candidates.len(),
+            total_bytes: candidates.iter().map(|(_, _, hash)| hash.size).sum(),
+            ..Default::default()
+        };
+        let selected: Vec<_> = candidates
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| *index < sample.largest || rng.next_percent() < sample.percent)
+            .map(|(_, candidate)| candidate)
+            .collect();
+        coverage.hashed_files = selected.len();
+        coverage.hashed_bytes = selected.iter().map(|(_, _, hash)| hash.size).sum();
+
+        let results: Vec<_> = stream::iter(selected)
+            .map(|(asset, destination, hash)| async move {
+                let result = self.check_file_hash(&asset, &destination, &hash.sha256).await;
+                (asset, destination, result)
+            })
+            .buffer_unordered(self.config.hash_concurrency.max(1))
+            .collect()
+            .await;
+
+        for (asset, destination, result) in results {
+            if let Err(e) = result {
+                coverage.failures += 1;
+                self.record_verify_failure(&asset, &destination, e).await;
+            }
+        }
+        coverage
+    }
+
+    /// Hashes a written file, decompressing it first when stored compressed
+    async fn check_file_hash(&self, asset: &DrupalFileAsset, destination: &Path, expected: &str) -> Result<()> {
+        let path = Self::local_file_path(asset, &self.config, destination);
+        let (actual, _) = Self::hash_file(&path, self.config.compresses(asset), &self.hash_limit).await?;
+        if actual != expected {
+            return Err(anyhow::anyhow!("sha256 mismatch: expected {} got {}", expected, actual));
+        }
+        Ok(())
+    }
+
+    /// Moves a verified-bad write from written to failed
+    async fn record_verify_failure(&self, asset: &DrupalFileAsset, destination: &Path, error: anyhow::Error) {
+        if let Some(report) = self
+            .destination_reports
+            .lock()
+            .await
+            .iter_mut()
+            .find(|report| report.destination == destination)
+        {
+            report.written -= 1;
+            report.failed += 1;
+        }
+        self.failed_downloads.lock().await.push(FailedDownload {
+            id: asset.id.clone(),
+            filename: asset.filename.clone(),
+            path: asset.path.clone(),
+            error: format!("Verification failed: {}", error),
+            timestamp: chrono::Utc::now(),
+            destination: Some(destination.display().to_string()),
+        });
+    }
+
+    /// Checks a written file against the asset's declared metadata
+    async fn verify_file(asset: &DrupalFileAsset, config: &DownloadConfig, destination: &Path) -> Result<()> {
+        let (_, size) = Self::local_metadata(asset, config, destination).await?;
+
+        if let Some(expected) = asset.size {
+            if !config.size_tolerance.matches(expected, size) {
+                return Err(anyhow::anyhow!(
+                    "size mismatch: expected {} got {}",
+                    expected,
+                    size
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Gets where an asset is written under a destination
+    pub fn local_file_path(asset: &DrupalFileAsset, config: &DownloadConfig, destination: &Path) -> PathBuf {
+        let filename = asset.local_filename(config.max_filename_length);
+        if config.compresses(asset) {
+            destination.join(format!("{}.gz", filename))
+        } else {
+            destination.join(filename)
+        }
+    }
+
+    pub fn get_download_url(asset: &DrupalFileAsset, config: &DownloadConfig) -> Result<String> {
+        // A manifest may give each file's full URL rather than a path under the base
+        if is_absolute_url(&asset.path) {
+            return Ok(asset.path.clone());
+        }
+
+        let base_url = config.base_url.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("Base URL is required for the relative path '{}' (see --base-url)", asset.path)
+        })?;
+
+        let base = base_url.trim_end_matches('/');
+        let path = config.path_separator.normalize(&asset.path);
+        let url = format!("{}/{}", base, path.trim_start_matches('/'));
+        
+        Ok(url)
+    }
+
+    /// Download URLs for an asset: `base_url` first, then each mirror
+    pub fn candidate_urls(asset: &DrupalFileAsset, config: &DownloadConfig) -> Result<Vec<String>> {
+        let mut urls = vec![Self::get_download_url(asset, config)?];
+        if is_absolute_url(&asset.path) {
+            return Ok(urls);
+        }
+        let path = config.path_separator.normalize(&asset.path);
+        for mirror in &config.mirror_urls {
+            urls.push(format!("{}/{}", mirror.trim_end_matches('/'), path.trim_start_matches('/')));
+        }
+        Ok(urls)
+    }
+
+    /// Whether `url` is served by `base_url` or a mirror, the only hosts
+    /// download credentials are sent to
+    fn trusted_url(url: &str, config: &DownloadConfig) -> bool {
+        let origin = |url: &str| reqwest::Url::parse(url).ok().map(|parsed| parsed.origin());
+        let Some(target) = origin(url) else {
+            return false;
+        };
+        config
+            .base_url
+            .iter()
+            .chain(&config.mirror_urls)
+            .any(|base| origin(base) == Some(target.clone()))
+    }
+
+    /// Adds the configured download credentials to a request for `url`.
+    /// Full URLs from the manifest on any other host go without them, so a
+    /// manifest can't send the credentials elsewhere.
+    fn authorize(request: reqwest::RequestBuilder, url: &str, config: &DownloadConfig) -> reqwest::RequestBuilder {
+        if !Self::trusted_url(url, config) {
+            request
+        } else if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            request.header(
+                AUTHORIZATION,
+                format!("Basic {}", base64_engine.encode(format!("{}:{}", username, password)))
+            )
+        } else if let Some(token) = &config.token {
+            request.bearer_auth(token)
+        } else {
+            request
+        }
+    }
+
+    /// Requests one URL into `spool`, waiting out 429 responses up to
+    /// `max_retries`. Returns the body size, modification time and cache
+    /// validators, or `None` when a conditional request came back 304.
+    async fn fetch_from(
+        url: &str,
+        asset: &DrupalFileAsset,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+        throttle: &AdaptiveThrottle,
+        cached: Option<&CacheEntry>,
+        spool: &Path,
+    ) -> Result<Option<(u64, Option<FileTime>, CacheEntry)>> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|parsed| parsed.host_str().map(str::to_string))
+            .unwrap_or_default();
+
+        let mut rate_limited = 0;
+        loop {
+            // Conditional requests are about whole files, so they never resume
+            let resume_from = match fs::metadata(spool).await {
+                Ok(metadata) if config.resume && cached.is_none() && metadata.len() > 0 => Some(metadata.len()),
+                _ => None,
+            };
+
+            let mut request = Self::authorize(client.get(url), url, config);
+            if let Some(offset) = resume_from {
+                request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+            }
+            if let Some(cached) = cached {
+                if let Some(etag) = &cached.etag {
+                    request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &cached.last_modified {
+                    request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+
+            throttle.acquire(&host).await;
+            let response = match tokio::time::timeout(config.idle_timeout(), request.send()).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(e)) => {
+                    throttle.release(&host, false).await;
+                    return Err(config.explain(url, e));
+                }
+                Err(_) => {
+                    throttle.release(&host, false).await;
+                    return Err(IdleTimeoutError {
+                        url: url.to_string(),
+                        seconds: config.download_timeout,
+                    }
+                    .into());
+                }
+            };
+
+            if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let retry_after = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .map(Duration::from_secs);
+                throttle.throttled(&host, retry_after).await;
+
+                rate_limited += 1;
+                if rate_limited > config.max_retries {
+                    return Err(anyhow::anyhow!(
+                        "Failed to download file: {} (status: {}, rate limited {} times)",
+                        url,
+                        response.status(),
+                        rate_limited
+                    ));
+                }
+                continue;
+            }
+
+            let status = response.status();
+            // A 200 to a Range request is handled below by truncating and starting over
+            if let Some(offset) = resume_from {
+                let unusable = status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+                    || (status == reqwest::StatusCode::PARTIAL_CONTENT
+                        && Self::content_range_start(&response) != Some(offset));
+                if unusable {
+                    // Drop the partial file and ask for the whole body
+                    throttle.release(&host, true).await;
+                    fs::remove_file(spool).await?;
+                    continue;
+                }
+            }
+            if status == reqwest::StatusCode::NOT_MODIFIED && cached.is_some() {
+                throttle.release(&host, true).await;
+                return Ok(None);
+            }
+            // Only cached by the caller once the content is verified and written
+            let validators = CacheEntry::from_headers(response.headers());
+
+            let modified = response
+                .headers()
+                .get(reqwest::header::LAST_MODIFIED)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| chrono::DateTime::parse_from_rfc2822(value).ok())
+                .map(|modified| FileTime::from_unix_time(modified.timestamp(), 0));
+
+            // The slot stays taken while the body streams in
+            let append = status == reqwest::StatusCode::PARTIAL_CONTENT;
+            let written = if status.is_success() {
+                Self::stream_to(response, spool, url, &asset.filename, config, throttle, append).await
+            } else {
+                Ok(0)
+            };
+            throttle.release(&host, status.is_success() && written.is_ok()).await;
+
+            if !status.is_success() {
+                return Err(HttpStatusError {
+                    url: url.to_string(),
+                    status,
+                }
+                .into());
+            }
+            let written = written?;
+            let size = if append { resume_from.unwrap_or(0) + written } else { written };
+            return Ok(Some((size, modified, validators)));
+        }
+    }
+
+    /// First byte offset of a 206 response's `Content-Range`
+    fn content_range_start(response: &reqwest::Response) -> Option<u64> {
+        response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)?
+            .to_str()
+            .ok()?
+            .strip_prefix("bytes ")?
+            .split('-')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    }
+
+    /// Writes a response body to `path` chunk by chunk, so memory use stays
+    /// flat whatever the file size, appending when `append` is set. Returns
+    /// the number of bytes written.
+    async fn stream_to(
+        mut response: reqwest::Response,
+        path: &Path,
+        url: &str,
+        name: &str,
+        config: &DownloadConfig,
+        throttle: &AdaptiveThrottle,
+        append: bool,
+    ) -> Result<u64> {
+        use tokio::io::AsyncWriteExt;
+        let bar = config
+            .progress
+            .as_ref()
+            .map(|progress| progress.start_file(name, response.content_length()));
+
+        let result = async {
+            let file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)
+                .await
+                .context(format!("Failed to create {}", path.display()))?;
+            let mut writer = tokio::io::BufWriter::new(file);
+            let mut written = 0;
+            loop {
+                let chunk = match tokio::time::timeout(config.idle_timeout(), response.chunk()).await {
+                    Ok(Ok(Some(chunk))) => chunk,
+                    Ok(Ok(None)) => break,
+                    // Keep what arrived so --resume can continue from it
+                    Ok(Err(e)) => {
+                        writer.flush().await?;
+                        return Err(config.explain(url, e));
+                    }
+                    Err(_) => {
+                        writer.flush().await?;
+                        return Err(IdleTimeoutError {
+                            url: url.to_string(),
+                            seconds: config.download_timeout,
+                        }
+                        .into());
+                    }
+                };
+                writer.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                throttle.consume(chunk.len() as u64).await;
+                if let Some(bar) = &bar {
+                    bar.inc(chunk.len() as u64);
+                }
+            }
+            writer.flush().await?;
+            Ok(written)
+        }
+        .await;
+
+        if let (Some(progress), Some(bar)) = (&config.progress, &bar) {
+            progress.finish_file(bar);
+        }
+        result
+    }
+
+    /// Rejects an asset whose local path would land outside a destination,
+    /// whether through `..`, an absolute path or a symlinked parent directory
+    async fn ensure_contained(asset: &DrupalFileAsset, config: &DownloadConfig, destinations: &[PathBuf]) -> Result<()> {
+        let filename = asset.local_filename(config.max_filename_length);
+        let relative = Path::new(&filename);
+        if filename.is_empty()
+            || relative
+                .components()
+                .any(|component| !matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(anyhow::anyhow!(
+                "Unsafe filename '{}': it would be written outside the destination",
+                asset.filename
+            ));
+        }
+
+        for destination in destinations {
+            // Nothing can be redirected until the destination exists
+            let Ok(root) = fs::canonicalize(destination).await else {
+                continue;
+            };
+            // The deepest directory that already exists decides where the file really lands
+            let target = destination.join(relative);
+            let mut parent = target.parent();
+            while let Some(dir) = parent {
+                if let Ok(real) = fs::canonicalize(dir).await {
+                    if !real.starts_with(&root) {
+                        return Err(anyhow::anyhow!(
+                            "Unsafe filename '{}': {} resolves to {}, outside {}",
+                            asset.filename,
+                            dir.display(),
+                            real.display(),
+                            root.display()
+                        ));
+                    }
+                    break;
+                }
+                parent = dir.parent();
+            }
+        }
+        Ok(())
+    }
+
+    /// Where a download is spooled before being written to its destinations
+    fn spool_path(asset: &DrupalFileAsset, config: &DownloadConfig, destination: &Path) -> PathBuf {
+        part_path(&destination.join(asset.local_filename(config.max_filename_length)))
+    }
+
+    /// Hashes a file with `file_digests` once a `hash_limit` permit is free
+    async fn hash_file(path: &Path, gzip: bool, hash_limit: &Semaphore) -> Result<(String, String)> {
+        // Hashing is CPU-bound, so besides running off the async workers it has its own limit
+        let _permit = hash_limit.acquire().await?;
+        file_digests(path, gzip).await
+    }
+
+    /// Fetches the asset once and writes it to each destination, in up to
+    /// `max_retries` attempts with backoff between them. Only transient
+    /// failures are retried.
+    ///
+    /// Returns an error when the fetch itself fails, `None` when the asset is
+    /// skipped, otherwise the content hash and one write outcome per
+    /// destination in the same order as `destinations`.
+    async fn download_single_file(
+        asset: &DrupalFileAsset,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+        resolver: Option<&UrlResolver>,
+        throttle: &AdaptiveThrottle,
+        hash_limit: &Semaphore,
+        destinations: &[PathBuf],
+    ) -> Result<Option<FetchOutcome>> {
+        Self::ensure_contained(asset, config, destinations).await?;
+
+        // The caller records what would have happened
+        if config.dry_run {
+            return Ok(None);
+        }
+
+        let attempts = config.max_retries.max(1);
+        let mut attempt = 1;
+        loop {
+            let result =
+                Self::attempt_download(asset, client, config, resolver, throttle, hash_limit, destinations).await;
+            match result {
+                Err(e) if is_transient(&e) && attempt < attempts => {
+                    let delay = Duration::from_millis(RETRY_BACKOFF_MS << (attempt - 1).min(6));
+                    status!(
+                        "Attempt {}/{} for {} failed, retrying in {}ms: {:#}",
+                        attempt,
+                        attempts,
+                        asset.filename,
+                        delay.as_millis(),
+                        e
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) if is_transient(&e) => {
+                    return Err(anyhow::anyhow!("{:#} (gave up after {} attempts)", e, attempt));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn attempt_download(
+        asset: &DrupalFileAsset,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+        resolver: Option<&UrlResolver>,
+        throttle: &AdaptiveThrottle,
+        hash_limit: &Semaphore,
+        destinations: &[PathBuf],
+    ) -> Result<Option<FetchOutcome>> {
+        if config.zero_byte == ZeroBytePolicy::Skip && asset.size == Some(0) {
+            return Ok(None);
+        }
+
+        // Content the store already holds is linked without any request
+        if let Some(cas_path) = Self::stored_in_cas(asset, config).await {
+            if Self::linked_to(asset, config, &cas_path, destinations).await {
+                return Ok(None);
+            }
+            if let Some(outcome) = Self::link_stored(asset, config, hash_limit, &cas_path, destinations).await? {
+                return Ok(Some(outcome));
+            }
+        }
+
+        // Only ask for a 304 when there is an intact local copy to keep
+        let cached = match &config.etag_cache {
+            Some(cache) if !config.force && Self::present_with_size(asset, config, destinations).await => {
+                cache.get(&asset.id).await
+            }
+            _ => None,
+        };
+
+        // Remote checks and conditional requests make their own skip decision
+        if !config.force
+            && !config.verify_remote_unchanged
+            && cached.is_none()
+            && Self::present_with_size(asset, config, destinations).await
+        {
+            return Ok(None);
+        }
+
+        if config.verify_remote_unchanged
+            && Self::remote_unchanged(asset, client, config, resolver, destinations).await
+        {
+            return Ok(None);
+        }
+
+        let urls = match resolver {
+            Some(resolver) => vec![resolver.resolve(asset, config).await?],
+            None => Self::candidate_urls(asset, config)?,
+        };
+
+        // The body lands next to the first destination, then is moved or copied into place
+        let spool = Self::spool_path(asset, config, &destinations[0]);
+        if let Some(parent) = spool.parent() {
+            create_dir_all_with_mode(parent, config.dir_mode).await?;
+        }
+        let outcome = Self::fetch_spooled(
+            asset,
+            client,
+            config,
+            throttle,
+            hash_limit,
+            destinations,
+            &urls,
+            cached.as_ref(),
+            &spool,
+        )
+        .await;
+        // An interrupted body is kept for --resume; anything else isn't needed any more
+        let interrupted = matches!(&outcome, Err(e) if e
+            .chain()
+            .any(|cause| cause.is::<reqwest::Error>() || cause.is::<IdleTimeoutError>()));
+        if !(config.resume && interrupted) {
+            let _ = fs::remove_file(&spool).await;
+        }
+        outcome
+    }
+
+    /// Fetches the asset into `spool` from the first URL that answers and
+    /// writes it to each destination
+    async fn fetch_spooled(
+        asset: &DrupalFileAsset,
+        client: &reqwest::Client,
+        config: &DownloadConfig,
+        throttle: &AdaptiveThrottle,
+        hash_limit: &Semaphore,
+        destinations: &[PathBuf],
+        urls: &[String],
+        cached: Option<&CacheEntry>,
+        spool: &Path,
+    ) -> Result<Option<FetchOutcome>> {
+        // Mirrors are tried in order; the first one that answers wins
+        let mut fetched = None;
+        for (index, candidate) in urls.iter().enumerate() {
+            match Self::fetch_from(candidate, asset, client, config, throttle, cached, spool).await {
+                Ok(result) => {
+                    fetched = Some((candidate.clone(), result));
+                    break;
+                }
+                Err(e) if index + 1 < urls.len() => {
+                    status!("{} failed, trying next mirror: {:#}", candidate, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let (url, size, modified, validators) = match fetched {
+            Some((url, Some((size, modified, validators)))) => (url, size, modified, validators),
+            Some((_, None)) => return Ok(None),
+            None => return Err(anyhow::anyhow!("No download URL for asset {}", asset.id)),
+        };
+
+        if size == 0 {
+            match (asset.size, config.zero_byte) {
+                // An empty body for a non-empty asset is never written
+                (Some(expected), _) if expected > 0 => {
+                    return Err(anyhow::anyhow!(
+                        "Empty response body for {} (expected {} bytes)",
+                        url,
+                        expected
+                    ));
+                }
+                (_, ZeroBytePolicy::Skip) => return Ok(None),
+                (None, ZeroBytePolicy::Fail) => {
+                    return Err(anyhow::anyhow!("Empty response body for {}", url));
+                }
+                _ => {}
+            }
+        }
+
+        // Checked on the body as received, before any rewriting
+        if let Some(expected) = asset.size {
+            if !config.size_tolerance.matches(expected, size) {
+                return Err(SizeMismatchError { expected, actual: size }.into());
+            }
+        }
+
+        let (received_sha256, received_md5) = Self::hash_file(spool, false, hash_limit).await?;
+        if let Some(expected) = &asset.sha256 {
+            if !expected.trim().eq_ignore_ascii_case(&received_sha256) {
+                return Err(ChecksumMismatchError {
+                    expected: expected.trim().to_lowercase(),
+                    actual: received_sha256,
+                }
+                .into());
+            }
+            status!("Checksum verified for {}", asset.filename);
+        }
+
+        // Only text files are rewritten, so reading them whole is fine
+        let transformed = config.transforms(asset);
+        let (size, (sha256, md5)) = if transformed {
+            let content = config.line_endings.normalize(&fs::read(spool).await?);
+            fs::write(spool, &content).await?;
+            (content.len() as u64, Self::hash_file(spool, false, hash_limit).await?)
+        } else {
+            (size, (received_sha256, received_md5))
+        };
+
+        let hash = FileHash {
+            path: if asset.path.is_empty() { asset.filename.clone() } else { asset.path.clone() },
+            size,
+            sha256,
+            md5,
+        };
+
+        // Compressed copies are written per destination, never linked from the store
+        let cas_path = match &config.cas_dir {
+            Some(cas_dir) if !config.compresses(asset) => {
+                Some(Self::store_in_cas(cas_dir, config, &hash.sha256, spool).await?)
+            }
+            _ => None,
+        };
+
+        // Files carry the remote modification time, falling back to the manifest's
+        let modified = modified.or_else(|| (asset.changed > 0).then(|| FileTime::from_unix_time(asset.changed, 0)));
+
+        // The first destination goes last, since its plain write takes the spool itself
+        let mut writes = Vec::with_capacity(destinations.len());
+        for destination in destinations.iter().rev() {
+            writes.push(match &cas_path {
+                Some(cas_path) => Self::link_from_cas(asset, config, cas_path, destination).await,
+                None => Self::write_file(asset, config, spool, destination, modified).await,
+            });
+        }
+        writes.reverse();
+
+        Ok(Some(FetchOutcome {
+            url,
+            hash,
+            writes,
+            validators,
+            transformed,
+        }))
+    }
+
+    /// Writes the spooled body to the asset's path under `destination`.
+    ///
+    /// The content goes to a sibling `.<name>.part` file that is renamed into
+    /// place once complete, so an interrupted write never leaves a truncated
+    /// file behind. When that part file is the spool itself it is just renamed.
+    async fn write_file(
+        asset: &DrupalFileAsset,
+        config: &DownloadConfig,
+        spool: &Path,
+        destination: &Path,
+        modified: Option<FileTime>,
+    ) -> Result<()> {
+        let dest_path = Self::local_file_path(asset, config, destination);
+        let part = part_path(&dest_path);
+
+        if let Some(parent) = dest_path.parent() {
+            create_dir_all_with_mode(parent, config.dir_mode).await?;
+        }
+
+        let result = Self::write_part(asset, config, spool, &part, &dest_path, modified).await;
+        if result.is_err() && part != spool {
+            let _ = fs::remove_file(&part).await;
+        }
+        result
+    }
+
+    async fn write_part(
+        asset: &DrupalFileAsset,
+        config: &DownloadConfig,
+        spool: &Path,
+        part: &Path,
+        dest_path: &Path,
+        modified: Option<FileTime>,
+    ) -> Result<()> {
+        if config.compresses(asset) {
+            use tokio::io::AsyncWriteExt;
+            let mut source = fs::File::open(spool).await?;
+            let file = fs::File::create(part).await?;
+            let mut encoder = async_compression::tokio::write::GzipEncoder::new(file);
+            tokio::io::copy(&mut source, &mut encoder).await?;
+            encoder.shutdown().await?;
+        } else if part != spool {
+            fs::copy(spool, part).await?;
+        }
+
+        // Set file permissions, rw-r--r-- unless --file-mode overrides it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(part, std::fs::Permissions::from_mode(config.file_mode)).await?;
+        }
+
+        // Renaming keeps the mtime, so set it on the part file
+        if let Some(modified) = modified {
+            filetime::set_file_mtime(part, modified)
+                .context(format!("Failed to set the modification time of {}", part.display()))?;
+        }
+
+        // The rename replaces a link left by a --cas-dir run rather than writing through it
+        fs::rename(part, dest_path)
+            .await
+            .context(format!("Failed to move {} into place", part.display()))?;
+        Ok(())
+    }
+
+    /// Stores `content` under `<cas_dir>/<hash[..2]>/<hash>` unless it's
+    /// already there, returning the stored path
+    async fn store_in_cas(
+        cas_dir: &Path,
+        config: &DownloadConfig,
+        sha256: &str,
+        spool: &Path,
+    ) -> Result<PathBuf> {
+        let cas_path = cas_dir.join(&sha256[..2]).join(sha256);
+        if fs::metadata(&cas_path).await.is_ok() {
+            return Ok(cas_path);
+        }
+
+        let parent = cas_path.parent().unwrap_or(cas_dir);
+        create_dir_all_with_mode(parent, config.dir_mode)
+            .await
+            .context(format!("Failed to create CAS directory {}", parent.display()))?;
+
+        // Concurrent downloads of the same content race harmlessly on the rename
+        let tmp_path = parent.join(format!("{}.{}.tmp", sha256, uuid::Uuid::new_v4()));
+        fs::copy(spool, &tmp_path)
+            .await
+            .context(format!("Failed to write {}", tmp_path.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(config.file_mode)).await?;
+        }
+        fs::rename(&tmp_path, &cas_path)
+            .await
+            .context(format!("Failed to move {} into the CAS", tmp_path.display()))?;
+
+        Ok(cas_path)
+    }
+
+    /// Where the CAS holds the asset's declared content, when it's there and
+    /// would be linked as is
+    async fn stored_in_cas(asset: &DrupalFileAsset, config: &DownloadConfig) -> Option<PathBuf> {
+        let cas_dir = config.cas_dir.as_ref()?;
+        if config.force || config.compresses(asset) || config.transforms(asset) {
+            return None;
+        }
+        let sha256 = asset.sha256.as_ref()?.trim().to_lowercase();
+        if sha256.len() != 64 {
+            return None;
+        }
+        let cas_path = cas_dir.join(&sha256[..2]).join(&sha256);
+        fs::metadata(&cas_path).await.is_ok().then_some(cas_path)
+    }
+
+    /// Whether every destination already links the asset to `cas_path`
+    async fn linked_to(asset: &DrupalFileAsset, config: &DownloadConfig, cas_path: &Path, destinations: &[PathBuf]) -> bool {
+        for destination in destinations {
+            let dest_path = Self::local_file_path(asset, config, destination);
+            if fs::read_link(&dest_path).await.ok().as_deref() != Some(cas_path) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Links every destination to content already in the CAS, after checking
+    /// the stored file still hashes to its name. Returns `None` when it
+    /// doesn't, so the asset is downloaded instead.
+    async fn link_stored(
+        asset: &DrupalFileAsset,
+        config: &DownloadConfig,
+        hash_limit: &Semaphore,
+        cas_path: &Path,
+        destinations: &[PathBuf],
+    ) -> Result<Option<FetchOutcome>> {
+        let (sha256, md5) = Self::hash_file(cas_path, false, hash_limit).await?;
+        if cas_path.file_name().and_then(|name| name.to_str()) != Some(sha256.as_str()) {
+            log::warn!("{} no longer matches its hash; downloading {} again", cas_path.display(), asset.filename);
+            return Ok(None);
+        }
+        status!("Linking {} from the CAS without downloading", asset.filename);
+
+        let mut writes = Vec::with_capacity(destinations.len());
+        for destination in destinations {
+            writes.push(Self::link_from_cas(asset, config, cas_path, destination).await);
+        }
+        Ok(Some(FetchOutcome {
+            url: cas_path.display().to_string(),
+            hash: FileHash {
+                path: if asset.path.is_empty() { asset.filename.clone() } else { asset.path.clone() },
+                size: fs::metadata(cas_path).await?.len(),
+                sha256,
+                md5,
+            },
+            writes,
+            validators: CacheEntry::default(),
+            transformed: false,
+        }))
+    }
+
+    /// Points the asset's local path at its content in the CAS
+    async fn link_from_cas(
+        asset: &DrupalFileAsset,
+        config: &DownloadConfig,
+        cas_path: &Path,
+        destination: &Path,
+    ) -> Result<()> {
+        let dest_path = Self::local_file_path(asset, config, destination);
+
+        if let Some(parent) = dest_path.parent() {
+            create_dir_all_with_mode(parent, config.dir_mode).await?;
+        }
+        if fs::symlink_metadata(&dest_path).await.is_ok() {
+            fs::remove_file(&dest_path).await?;
+        }
+
+        #[cfg(unix)]
+        fs::symlink(cas_path, &dest_path).await?;
+        #[cfg(windows)]
+        fs::symlink_file(cas_path, &dest_path).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(mime: &str, size: u64) -> DrupalFileAsset {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "filename": "file",
+            "uri": "public://file",
+            "mime": mime,
+            "size": size,
+        }))
+        .unwrap()
+    }
+
+    fn config(line_endings: LineEndings) -> DownloadConfig {
+        DownloadConfig {
+            line_endings,
+            ..DownloadConfig::default()
+        }
+    }
+
+    #[test]
+    fn binary_assets_are_never_rewritten() {
+        // PNG signature, which contains both \r\n and a lone \n
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        for line_endings in [LineEndings::Lf, LineEndings::Crlf] {
+            let config = config(line_endings);
+            for mime in ["image/png", "application/octet-stream", "application/pdf"] {
+                let asset = asset(mime, png.len() as u64);
+                assert!(!config.transforms(&asset), "{} would be rewritten", mime);
+                assert!(config.matches_declared_size(&asset, png.len() as u64));
+                assert!(!config.matches_declared_size(&asset, png.len() as u64 - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn text_assets_are_normalized() {
+        assert_eq!(LineEndings::Lf.normalize(b"a\r\nb\nc\r"), b"a\nb\nc\r");
+        assert_eq!(LineEndings::Crlf.normalize(b"a\r\nb\n"), b"a\r\nb\r\n");
+        assert!(config(LineEndings::Lf).transforms(&asset("text/plain", 4)));
+        assert!(!config(LineEndings::None).transforms(&asset("text/plain", 4)));
+    }
+
+    #[test]
+    fn rewritten_text_skips_the_declared_size_check() {
+        let asset = asset("text/csv", 100);
+        assert!(config(LineEndings::Lf).matches_declared_size(&asset, 96));
+        assert!(!config(LineEndings::None).matches_declared_size(&asset, 96));
     }
 }
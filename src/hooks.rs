@@ -0,0 +1,112 @@
+use anyhow::Result;
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::process::Command;
+
+use crate::schema::{mime_matches, DrupalFileAsset};
+
+/// External command run on every downloaded file whose MIME type matches
+#[derive(Debug, Clone)]
+pub struct PostHook {
+    /// MIME pattern: exact (`image/png`), type wildcard (`image/*`) or `*`
+    pub pattern: String,
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl PostHook {
+    /// Parses `<mime-pattern>:<command>`. The command is split on whitespace
+    /// and run without a shell; the file path is appended as the last argument.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (pattern, command) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <mime-pattern>:<command>, got '{}'", s))?;
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words
+            .next()
+            .ok_or_else(|| format!("missing command in '{}'", s))?;
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(format!("missing MIME pattern in '{}'", s));
+        }
+        Ok(Self {
+            pattern: pattern.to_lowercase(),
+            program,
+            args: words.collect(),
+        })
+    }
+
+    pub fn matches(&self, mime: &str) -> bool {
+        mime_matches(&self.pattern, mime)
+    }
+
+    fn describe(&self) -> String {
+        format!("{}:{}", self.pattern, self.program)
+    }
+}
+
+/// A hook that failed, timed out or couldn't be started
+#[derive(Debug, Clone, Serialize)]
+pub struct HookFailure {
+    pub hook: String,
+    pub id: String,
+    pub path: String,
+    pub error: String,
+}
+
+/// Runs every matching hook on each written file, at most `concurrency`
+/// at a time, killing any hook that runs longer than `timeout`
+pub async fn run_post_hooks(
+    hooks: &[PostHook],
+    files: &[(DrupalFileAsset, PathBuf)],
+    concurrency: usize,
+    timeout: Duration,
+) -> (usize, Vec<HookFailure>) {
+    let jobs: Vec<(&PostHook, &DrupalFileAsset, &PathBuf)> = files
+        .iter()
+        .flat_map(|(asset, path)| {
+            hooks
+                .iter()
+                .filter(|hook| hook.matches(&asset.mime))
+                .map(move |hook| (hook, asset, path))
+        })
+        .collect();
+    let total = jobs.len();
+
+    let failures: Vec<HookFailure> = stream::iter(jobs)
+        .map(|(hook, asset, path)| async move {
+            let result = run_hook(hook, path, timeout).await;
+            result.err().map(|error| HookFailure {
+                hook: hook.describe(),
+                id: asset.id.clone(),
+                path: path.display().to_string(),
+                error,
+            })
+        })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(|failure| async move { failure })
+        .collect()
+        .await;
+
+    (total, failures)
+}
+
+async fn run_hook(hook: &PostHook, path: &PathBuf, timeout: Duration) -> Result<(), String> {
+    let child = Command::new(&hook.program)
+        .args(&hook.args)
+        .arg(path)
+        .kill_on_drop(true)
+        .output();
+
+    match tokio::time::timeout(timeout, child).await {
+        Err(_) => Err(format!("timed out after {}s", timeout.as_secs())),
+        Ok(Err(e)) => Err(format!("failed to start {}: {}", hook.program, e)),
+        Ok(Ok(output)) if !output.status.success() => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(format!("exited with {}: {}", output.status, stderr.trim()))
+        }
+        Ok(Ok(_)) => Ok(()),
+    }
+}
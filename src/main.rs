@@ -1,24 +1,68 @@
 #![allow(warnings)]
 
+#[macro_use]
+mod output;
+
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use std::path::{Path, PathBuf};
 use std::env;
 use tokio::fs;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde_json;
 
-use crate::schema::{DrupalFileAsset, DrupalFileAssetsWrapper, DrupalFileAssetsResponse};
-use crate::downloader::{Downloader, DownloadConfig};
+use crate::schema::{
+    DrupalFileAsset, DrupalFileAssetsWrapper, DrupalFileAssetsResponse, FieldMap, Manifest, ManifestPatch, PathFilter,
+};
+use crate::downloader::{
+    ChangeAction, Downloader, DownloadConfig, LineEndings, PathSeparator, PlannedChange, SizeTolerance, TlsVersion,
+    VerifySample, ZeroBytePolicy,
+};
 use crate::config::CliConfig;
+use crate::queue::WorkQueue;
+use crate::secrets::{Credentials, Secrets};
+use crate::assets::{AssetListingConfig, ListingFormat};
+use crate::docs::{DocFormat, DocGenerator, UserRole};
+use crate::hooks::PostHook;
+use crate::cache::{EtagCache, STATE_FILE};
+use crate::progress::SyncProgress;
+use crate::managed::ManagedFiles;
+use crate::output::LogFormat;
+use std::io::IsTerminal;
+use crate::reporting::{
+    DedupeReport, FailureRecord, LogManager, ReportFormat, ReportNameTemplate, ReportWriter, SqliteWriter, SyncRecord,
+    SyncSummary,
+};
 
 mod schema;
 mod downloader;
 mod config;
+mod reporting;
+mod queue;
+mod secrets;
+mod assets;
+mod throttle;
+mod docs;
+mod hooks;
+mod cache;
+mod progress;
+mod managed;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    /// Directory to load and save configuration from (overrides the platform default)
+    #[arg(long, global = true)]
+    config_dir: Option<PathBuf>,
+
+    /// Only print warnings, errors and command results
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also print debug detail such as manifest previews and field lists
+    #[arg(long, short, global = true)]
+    verbose: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -27,17 +71,19 @@ struct Cli {
 enum Commands {
     /// Sync files from a remote source
     Sync {
-        /// Path to assets metadata file or URL
+        /// Path to assets metadata file or URL. A local glob pattern such as
+        /// 'manifests/*.json' merges every matching shard, deduped by id
         #[arg(long)]
         assets_metadata: Option<String>,
 
-        /// Destination directory for downloaded files
+        /// Destination directory for downloaded files (repeat to mirror into several locations)
         #[arg(long)]
-        destination: Option<PathBuf>,
+        destination: Vec<PathBuf>,
 
-        /// Base URL for file downloads
+        /// Base URL for file downloads; repeat to list mirrors tried in order
+        /// when a download from the previous one fails (defaults to the config profile's)
         #[arg(long)]
-        base_url: String,
+        base_url: Vec<String>,
 
         /// Maximum number of concurrent downloads
         #[arg(long, default_value_t = 4)]
@@ -59,6 +105,366 @@ enum Commands {
         #[arg(long)]
         download_password: Option<String>,
 
+        /// Pause each download slot takes before its next download, in milliseconds
+        /// (defaults to the config profile's, else 100)
+        #[arg(long)]
+        download_delay: Option<u64>,
+
+        /// Seconds a download may wait for a response or the next chunk of its
+        /// body before it is retried (defaults to the config profile's, else 60)
+        #[arg(long)]
+        download_timeout: Option<u64>,
+
+        /// Seconds allowed for DNS and TCP/TLS connection setup, separate from --download-timeout
+        #[arg(long, default_value_t = 10)]
+        connect_timeout: u64,
+
+        /// Maximum number of retries for failed downloads (defaults to the config profile's, else 3)
+        #[arg(long)]
+        max_retries: Option<usize>,
+
+        /// Force download even if file exists
+        #[arg(long)]
+        force: bool,
+
+        /// Previous manifest to diff against; only new or changed assets are downloaded
+        #[arg(long)]
+        diff_base: Option<PathBuf>,
+
+        /// Verify written files against their metadata once downloads finish
+        #[arg(long)]
+        verify_after_sync: bool,
+
+        /// Write a report of files with identical content to this path
+        #[arg(long)]
+        dedupe_report: Option<PathBuf>,
+
+        /// Append this run's sync and failure records to a SQLite database
+        #[arg(long)]
+        sqlite: Option<PathBuf>,
+
+        /// Resolver URL template returning the real download URL, e.g. https://broker/sign?path={path}
+        #[arg(long)]
+        resolve_endpoint: Option<String>,
+
+        /// JSON field holding the resolved URL when the resolver doesn't redirect
+        #[arg(long, default_value = "url")]
+        resolve_field: String,
+
+        /// Longest filename written locally in bytes; longer names are truncated with a hash suffix
+        #[arg(long, default_value_t = schema::DEFAULT_MAX_FILENAME_LENGTH, value_parser = parse_filename_length)]
+        max_filename_length: usize,
+
+        /// Resume an ordered run by skipping every asset up to and including this id
+        #[arg(long)]
+        continue_from_id: Option<String>,
+
+        /// How to handle empty response bodies
+        #[arg(long, value_enum, default_value_t = ZeroBytePolicy::Allow)]
+        zero_byte: ZeroBytePolicy,
+
+        /// Track work in a persistent queue (queue.json in the destination) so a sync can span several runs
+        #[arg(long)]
+        queue: bool,
+
+        /// Discard the persistent queue and start fresh (implies --queue)
+        #[arg(long)]
+        reset_queue: bool,
+
+        /// Treat sizes within this many bytes (e.g. 512) or percent (e.g. 1%) of the declared size as matching
+        #[arg(long, default_value = "0")]
+        size_tolerance: SizeTolerance,
+
+        /// Verify each file right after its download, re-fetching it on mismatch
+        #[arg(long)]
+        verify_inline: bool,
+
+        /// JSON or TOML file with `source`/`download` credentials; CLI flags take precedence
+        #[arg(long)]
+        secrets_file: Option<PathBuf>,
+
+        /// Normalize line endings of text/* assets before writing
+        #[arg(long, value_enum, default_value_t = LineEndings::None)]
+        normalize_line_endings: LineEndings,
+
+        /// HTTP method used to fetch the assets metadata
+        #[arg(long, value_enum, ignore_case = true, default_value_t = MetadataMethod::Get)]
+        metadata_method: MetadataMethod,
+
+        /// Request body for POST metadata fetches, inline or `@file`
+        #[arg(long)]
+        metadata_body: Option<String>,
+
+        /// Content-Type sent with --metadata-body
+        #[arg(long, default_value = "application/json")]
+        metadata_content_type: String,
+
+        /// JSON pointer to the next-page link in a paginated metadata response
+        /// (a URL string, or an object with an `href`)
+        #[arg(long, default_value = "/links/next")]
+        next_page_pointer: String,
+
+        /// Most metadata pages to fetch; the sync fails rather than run on a partial list
+        #[arg(long, default_value_t = 1000)]
+        max_pages: usize,
+
+        /// Keep running, re-syncing every --interval seconds until Ctrl-C
+        #[arg(long)]
+        watch: bool,
+
+        /// Seconds to sleep between --watch cycles
+        #[arg(long, default_value_t = 300)]
+        interval: u64,
+
+        /// Create the manifest's `directories` even when no file lands in them
+        #[arg(long)]
+        preserve_empty_dirs: bool,
+
+        /// Concurrency caps per file extension, e.g. `jpg=20,mp4=2`
+        #[arg(long, value_delimiter = ',', value_parser = parse_ext_limit)]
+        ext_concurrency: Vec<(String, usize)>,
+
+        /// Store file bytes once in this content-addressed directory and build
+        /// the destinations from symlinks into it
+        #[arg(long)]
+        cas_dir: Option<PathBuf>,
+
+        /// Print which assets would be added, updated or left unchanged, using
+        /// HEAD requests for files already present, without downloading
+        #[arg(long)]
+        dry_run_diff: bool,
+
+        /// Seconds over which concurrency grows linearly from 1 to --max-concurrent
+        #[arg(long, default_value_t = 0)]
+        ramp_up: u64,
+
+        /// Run a command on each downloaded file whose MIME type matches, as
+        /// `<mime-pattern>:<command>` (repeatable); the file path is appended
+        #[arg(long, value_parser = PostHook::parse)]
+        post_hook: Vec<PostHook>,
+
+        /// Seconds before a post-hook is killed
+        #[arg(long, default_value_t = 60)]
+        post_hook_timeout: u64,
+
+        /// Fail the sync when any post-hook fails
+        #[arg(long)]
+        fail_on_hook_error: bool,
+
+        /// Base manifest to apply --patch-manifest files to (alternative to --assets-metadata)
+        #[arg(long, conflicts_with = "assets_metadata")]
+        base_manifest: Option<String>,
+
+        /// Patch manifest of `add`/`update`/`remove` entries applied on top of
+        /// the manifest, by id (repeatable, applied in order)
+        #[arg(long)]
+        patch_manifest: Vec<String>,
+
+        /// Directory to keep a sync log of every run in, in the --report-format formats
+        #[arg(long)]
+        report_dir: Option<PathBuf>,
+
+        /// Number of sync logs kept in --report-dir
+        #[arg(long, default_value_t = 10)]
+        max_reports: u32,
+
+        /// Remove sync logs older than this many days from --report-dir
+        #[arg(long)]
+        report_retention_days: Option<u32>,
+
+        /// Skip files already present with the declared size when a HEAD request
+        /// shows the remote copy's size and Last-Modified are unchanged
+        #[arg(long)]
+        verify_remote_unchanged: bool,
+
+        /// Oldest TLS version to negotiate with the metadata source, resolver and
+        /// download servers
+        #[arg(long, value_enum, default_value = "1.2")]
+        min_tls_version: TlsVersion,
+
+        /// ETag/Last-Modified cache for conditional requests, keyed by asset id.
+        /// Files already present are only re-fetched when the server reports a change.
+        /// Defaults to .sync-state.json in the first destination
+        #[arg(long)]
+        etag_cache: Option<PathBuf>,
+
+        /// Don't keep an ETag cache; present files are skipped on size alone
+        #[arg(long, conflicts_with = "etag_cache")]
+        no_etag_cache: bool,
+
+        /// Files hashed at once, independent of --max-concurrent (defaults to the CPU count)
+        #[arg(long)]
+        hash_concurrency: Option<usize>,
+
+        /// Separator used by manifest paths: auto treats backslashes as separators
+        /// only in paths without a forward slash
+        #[arg(long, value_enum, default_value = "auto")]
+        path_separator: PathSeparator,
+
+        /// Abort when the manifest's generated timestamp is older than this (e.g. 30m, 12h, 7d)
+        #[arg(long, value_parser = parse_duration)]
+        max_manifest_age: Option<u64>,
+
+        /// Gzip assets whose MIME type matches (e.g. text/*, application/json) and
+        /// store them as <name>.gz; repeat for several patterns
+        #[arg(long)]
+        store_compressed: Vec<String>,
+
+        /// Abort the sync when the first downloads are all rejected with 401/403
+        #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+        abort_on_auth_failure: bool,
+
+        /// File name template for --report-dir logs; placeholders: {profile}, {date}, {time}, {sync_id}
+        #[arg(long, value_parser = ReportNameTemplate::parse, default_value = ReportNameTemplate::DEFAULT)]
+        report_name: ReportNameTemplate,
+
+        /// Save assets.json with files sorted by id and keys sorted, pretty-printed,
+        /// so diffs between runs show only real content changes
+        #[arg(long)]
+        canonicalize_manifest: bool,
+
+        /// Verify all file sizes, then re-hash this percentage of the written files
+        /// (plus the largest ones) against the downloaded content
+        #[arg(long, value_parser = parse_percent)]
+        verify_sample: Option<f64>,
+
+        /// Largest files always re-hashed by --verify-sample
+        #[arg(long, default_value_t = 10)]
+        verify_sample_largest: usize,
+
+        /// Seed for --verify-sample's random pick, for reproducible runs
+        #[arg(long)]
+        verify_sample_seed: Option<u64>,
+
+        /// Octal permissions for synced files, e.g. 640 (Unix only)
+        #[arg(long, value_parser = parse_mode, default_value = "644")]
+        file_mode: u32,
+
+        /// Octal permissions for directories created during the sync, e.g. 750 (Unix only)
+        #[arg(long, value_parser = parse_mode)]
+        dir_mode: Option<u32>,
+
+        /// Base path for the failed-download report, written as .csv and .json
+        /// [default: <destination>/failed_downloads]
+        #[arg(long)]
+        failures_report: Option<PathBuf>,
+
+        /// Best-effort cap on the combined download rate, e.g. 500KB or 2MB
+        /// (per second; K/M/G are powers of 1024)
+        #[arg(long, value_parser = parse_bandwidth)]
+        max_bandwidth: Option<u64>,
+
+        /// Continue downloads interrupted by a previous run from their .part
+        /// files using HTTP Range requests
+        #[arg(long)]
+        resume: bool,
+
+        /// Show an overall progress bar and one per download in flight
+        /// (ignored when stdout is not a terminal)
+        #[arg(long)]
+        progress: bool,
+
+        /// Print what the sync would add, update, skip and delete, and the bytes
+        /// to transfer, without sending download requests or writing files
+        #[arg(long, conflicts_with = "dry_run_diff")]
+        dry_run: bool,
+
+        /// Send a HEAD request for every asset and report its status, size and
+        /// type against the metadata, without downloading. Results go to
+        /// precheck.csv/.json in --report-dir, or the destination
+        #[arg(long, conflicts_with_all = ["dry_run", "dry_run_diff"])]
+        check_only: bool,
+
+        /// Map keys of a non-Drupal manifest onto asset fields as SOURCE=FIELD pairs,
+        /// e.g. 'name=filename,url=path,hash=sha256'
+        #[arg(long, value_delimiter = ',', value_parser = schema::parse_field_mapping)]
+        field_map: Vec<(String, String)>,
+
+        /// Saved configuration profile supplying the base URL, credentials and
+        /// download settings no flag sets (defaults to `default` when saved)
+        #[arg(long)]
+        config_id: Option<String>,
+
+        /// Formats of each --report-dir sync log (comma-separated: csv, json, html)
+        #[arg(long, value_enum, value_delimiter = ',', default_values_t = [ReportFormat::Csv, ReportFormat::Json], requires = "report_dir")]
+        report_format: Vec<ReportFormat>,
+
+        /// Output style: `text` status lines, or `jsonl` with one JSON object per
+        /// downloaded, skipped or failed file and a final summary
+        #[arg(long, value_enum, default_value_t = LogFormat::Text, conflicts_with_all = ["dry_run", "dry_run_diff", "check_only"])]
+        log_format: LogFormat,
+
+        /// Delete files earlier syncs wrote that are no longer in the metadata;
+        /// without it they are only listed
+        #[arg(long)]
+        delete: bool,
+
+        /// Print only a final summary block instead of progress output
+        #[arg(long)]
+        summary_only: bool,
+
+        /// Only sync assets carrying one of these tags (comma-separated or repeated)
+        #[arg(long, value_delimiter = ',')]
+        include_tag: Vec<String>,
+
+        /// Skip assets carrying any of these tags (takes precedence over --include-tag)
+        #[arg(long, value_delimiter = ',')]
+        exclude_tag: Vec<String>,
+
+        /// Only include assets whose MIME type matches one of these globs, e.g. 'image/*'
+        /// (comma-separated or repeated)
+        #[arg(long, value_delimiter = ',', value_parser = parse_mime_pattern)]
+        include_mime: Vec<glob::Pattern>,
+
+        /// Skip assets with any of these file extensions, e.g. 'pdf,zip'
+        #[arg(long, value_delimiter = ',', value_parser = parse_extension)]
+        exclude_ext: Vec<String>,
+
+        /// Only include assets whose path or filename matches one of these globs,
+        /// e.g. 'sites/*/files/**' (repeat for several)
+        #[arg(long = "include", value_parser = parse_path_glob)]
+        include_path: Vec<String>,
+
+        /// Skip assets whose path or filename matches any of these globs, e.g. '*.tmp'
+        /// (repeat for several; takes precedence over --include)
+        #[arg(long = "exclude", value_parser = parse_path_glob)]
+        exclude_path: Vec<String>,
+
+        /// Only run the sync when an environment variable matches (KEY=VALUE)
+        #[arg(long, value_parser = parse_env_condition)]
+        run_if_env: Option<(String, String)>,
+    },
+
+    /// Configure the CLI, or list, show and delete saved configurations
+    #[command(args_conflicts_with_subcommands = true)]
+    Config {
+        #[command(subcommand)]
+        action: Option<ConfigAction>,
+
+        /// Base URL for file downloads
+        #[arg(long)]
+        base_url: Option<String>,
+
+        /// Default destination path for downloads
+        #[arg(long)]
+        desti_path: Option<String>,
+
+        /// Username for metadata source
+        #[arg(long)]
+        source_username: Option<String>,
+
+        /// Password for metadata source
+        #[arg(long)]
+        source_password: Option<String>,
+
+        /// Username for file downloads
+        #[arg(long)]
+        download_username: Option<String>,
+
+        /// Password for file downloads
+        #[arg(long)]
+        download_password: Option<String>,
+
         /// Delay between downloads in milliseconds
         #[arg(long, default_value_t = 100)]
         download_delay: u64,
@@ -74,17 +480,112 @@ enum Commands {
         /// Force download even if file exists
         #[arg(long)]
         force: bool,
+
+        /// Minimum seconds between syncs using this profile; 0 removes the limit
+        #[arg(long)]
+        ttl: Option<u64>,
+
+        /// Configuration profile to update
+        #[arg(long, default_value = "default")]
+        config_id: String,
     },
+    /// Generate a listing of the files in a local directory
+    Listing {
+        /// Directory to list
+        #[arg(long)]
+        dir: PathBuf,
 
-    /// Configure the CLI
-    Config {
-        /// Base URL for file downloads
+        /// Base URL used to build each entry's download URL
         #[arg(long)]
         base_url: Option<String>,
 
-        /// Default destination path for downloads
+        /// Output format of the listing
+        #[arg(long, value_enum, default_value_t = ListingFormat::Json)]
+        output_listing_format: ListingFormat,
+
+        /// File to write the listing to (defaults to assets.<format> inside --dir)
         #[arg(long)]
-        desti_path: Option<String>,
+        output: Option<PathBuf>,
+
+        /// List the files symlinks point to instead of skipping them
+        #[arg(long)]
+        follow_symlinks: bool,
+
+        /// Directory levels to descend below --dir (0 lists only its own files)
+        #[arg(long)]
+        max_depth: Option<usize>,
+
+        /// Gitignore-style file of paths to leave out of the listing
+        #[arg(long)]
+        ignore_file: Option<PathBuf>,
+
+        /// List paths the built-in excludes (.git, node_modules, *.tmp, ...) would skip
+        #[arg(long)]
+        no_default_excludes: bool,
+
+        /// Record each file's SHA-256 in the listing (and as `sha256` in manifests)
+        #[arg(long)]
+        checksums: bool,
+
+        /// Files read at once while building the listing
+        #[arg(long, default_value_t = 4)]
+        listing_concurrency: usize,
+    },
+    /// Generate the tool's reference documentation
+    Docs {
+        /// Directory to write the documentation to
+        #[arg(long, default_value = "docs")]
+        output: PathBuf,
+
+        /// Output format of the documentation tables; markdown writes a
+        /// single docs.md
+        #[arg(long, value_enum, default_value_t = DocFormat::Csv)]
+        format: DocFormat,
+
+        /// Write every table into a single docs.json (requires --format json)
+        #[arg(long)]
+        combined: bool,
+
+        /// Audience whose documentation is written: admin sees every row,
+        /// developer sees developer and general rows, all sees general rows only
+        #[arg(long, value_enum, default_value_t = UserRole::Admin)]
+        role: UserRole,
+    },
+    /// Check a manifest for problems without downloading any files
+    Validate {
+        /// Path to assets metadata file or URL
+        #[arg(long)]
+        assets_metadata: String,
+
+        /// Username for metadata source
+        #[arg(long)]
+        source_username: Option<String>,
+
+        /// Password for metadata source
+        #[arg(long)]
+        source_password: Option<String>,
+
+        /// Longest local filename in bytes, used to detect name collisions
+        #[arg(long, default_value_t = schema::DEFAULT_MAX_FILENAME_LENGTH, value_parser = parse_filename_length)]
+        max_filename_length: usize,
+
+        /// JSON pointer to the next-page link in a paginated metadata response
+        #[arg(long, default_value = "/links/next")]
+        next_page_pointer: String,
+
+        /// Most metadata pages to fetch; more fails rather than run on a partial list
+        #[arg(long, default_value_t = 1000)]
+        max_pages: usize,
+
+        /// Report format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// List the assets a sync would consider, without downloading anything
+    List {
+        /// Path to assets metadata file or URL; a local glob merges matching shards
+        #[arg(long)]
+        assets_metadata: String,
 
         /// Username for metadata source
         #[arg(long)]
@@ -94,221 +595,2153 @@ enum Commands {
         #[arg(long)]
         source_password: Option<String>,
 
-        /// Username for file downloads
-        #[arg(long)]
-        download_username: Option<String>,
+        /// Previous manifest to diff against; only new or changed assets are listed
+        #[arg(long)]
+        diff_base: Option<PathBuf>,
+
+        /// JSON pointer to the next-page link in a paginated metadata response
+        #[arg(long, default_value = "/links/next")]
+        next_page_pointer: String,
+
+        /// Most metadata pages to fetch; more fails rather than run on a partial list
+        #[arg(long, default_value_t = 1000)]
+        max_pages: usize,
+
+        /// Skip every asset up to and including this id
+        #[arg(long)]
+        continue_from_id: Option<String>,
+
+        /// Only list assets carrying one of these tags (comma-separated or repeated)
+        #[arg(long, value_delimiter = ',')]
+        include_tag: Vec<String>,
+
+        /// Skip assets carrying any of these tags (takes precedence over --include-tag)
+        #[arg(long, value_delimiter = ',')]
+        exclude_tag: Vec<String>,
+
+        /// Only include assets whose MIME type matches one of these globs, e.g. 'image/*'
+        /// (comma-separated or repeated)
+        #[arg(long, value_delimiter = ',', value_parser = parse_mime_pattern)]
+        include_mime: Vec<glob::Pattern>,
+
+        /// Skip assets with any of these file extensions, e.g. 'pdf,zip'
+        #[arg(long, value_delimiter = ',', value_parser = parse_extension)]
+        exclude_ext: Vec<String>,
+
+        /// Only include assets whose path or filename matches one of these globs,
+        /// e.g. 'sites/*/files/**' (repeat for several)
+        #[arg(long = "include", value_parser = parse_path_glob)]
+        include_path: Vec<String>,
+
+        /// Skip assets whose path or filename matches any of these globs, e.g. '*.tmp'
+        /// (repeat for several; takes precedence over --include)
+        #[arg(long = "exclude", value_parser = parse_path_glob)]
+        exclude_path: Vec<String>,
+
+        /// Table or JSON output
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output: OutputFormat,
+    },
+    /// Print the JSON Schema of the assets metadata format
+    Schema {
+        /// Write the schema to this file instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Management of saved configurations
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// List saved configurations with their base URL and last sync
+    List,
+    /// Print one configuration with passwords masked
+    Show {
+        /// Configuration id
+        #[arg(long)]
+        id: String,
+    },
+    /// Delete a saved configuration
+    Delete {
+        /// Configuration id
+        #[arg(long)]
+        id: String,
+    },
+}
+
+/// Format of command reports written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// HTTP method used to fetch the assets metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum MetadataMethod {
+    Get,
+    Post,
+}
+
+/// How the assets metadata is requested from an HTTP source
+#[derive(Debug, Clone)]
+struct MetadataRequest {
+    method: MetadataMethod,
+    body: Option<String>,
+    content_type: String,
+    /// JSON pointer to the next page's link; later pages are plain GETs
+    next_page_pointer: String,
+    max_pages: usize,
+}
+
+impl MetadataRequest {
+    /// Builds the request from the CLI flags, reading `@file` bodies from disk
+    async fn from_args(
+        method: MetadataMethod,
+        body: Option<String>,
+        content_type: String,
+        next_page_pointer: String,
+        max_pages: usize,
+    ) -> Result<Self> {
+        let body = match body {
+            Some(body) => match body.strip_prefix('@') {
+                Some(path) => Some(
+                    fs::read_to_string(path)
+                        .await
+                        .context(format!("Failed to read metadata body from {}", path))?,
+                ),
+                None => Some(body),
+            },
+            None => None,
+        };
+
+        if body.is_some() && method == MetadataMethod::Get {
+            return Err(anyhow::anyhow!("--metadata-body requires --metadata-method POST"));
+        }
+        if let Some(body) = &body {
+            if content_type.contains("json") {
+                serde_json::from_str::<serde_json::Value>(body)
+                    .context("--metadata-body is not valid JSON")?;
+            }
+        }
+
+        Ok(Self {
+            method,
+            body,
+            content_type,
+            next_page_pointer,
+            max_pages: max_pages.max(1),
+        })
+    }
+
+    /// A plain GET that follows next-page links, as `list` and `validate` read
+    fn paged(next_page_pointer: String, max_pages: usize) -> Self {
+        Self {
+            method: MetadataMethod::Get,
+            body: None,
+            content_type: "application/json".to_string(),
+            next_page_pointer,
+            max_pages: max_pages.max(1),
+        }
+    }
+
+    fn build(&self, client: &reqwest::Client, source: &str) -> reqwest::RequestBuilder {
+        match self.method {
+            MetadataMethod::Get => client.get(source),
+            MetadataMethod::Post => client
+                .post(source)
+                .header(reqwest::header::CONTENT_TYPE, &self.content_type)
+                .body(self.body.clone().unwrap_or_default()),
+        }
+    }
+}
+
+/// Parses a percentage between 0 and 100
+fn parse_percent(s: &str) -> Result<f64, String> {
+    s.trim()
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("expected a percentage between 0 and 100, got '{}'", s))
+}
+
+/// Parses a rate such as `500KB`, `2MB` or `1.5M` (optionally `/s`) into
+/// bytes per second; K, M and G are powers of 1024
+fn parse_bandwidth(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim().trim_end_matches("/s");
+    let upper = trimmed.to_uppercase();
+    let number = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match upper[number.len()..].trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("expected a rate such as 500KB or 2MB, got '{}'", s)),
+    };
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|value| *value > 0.0)
+        .map(|value| (value * multiplier as f64) as u64)
+        .ok_or_else(|| format!("expected a rate such as 500KB or 2MB, got '{}'", s))
+}
+
+/// Parses a Unix permission mode written in octal, e.g. `750` or `0o750`
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.trim().trim_start_matches("0o");
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("expected an octal mode such as 644 or 0750, got '{}'", s))
+}
+
+/// Parses a duration such as `90`, `30m`, `12h` or `7d` into seconds
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("expected a duration like 30m, 12h or 7d, got '{}'", s))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("unknown duration unit '{}' in '{}' (use s, m, h or d)", unit, s)),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("duration '{}' is too large", s))
+}
+
+/// Parses an `EXT=N` extension concurrency cap
+fn parse_ext_limit(s: &str) -> Result<(String, usize), String> {
+    let (ext, cap) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected EXT=N, got '{}'", s))?;
+    let ext = ext.trim().trim_start_matches('.').to_lowercase();
+    if ext.is_empty() {
+        return Err(format!("missing extension in '{}'", s));
+    }
+    let cap = cap
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|cap| *cap > 0)
+        .ok_or_else(|| format!("expected a positive concurrency in '{}'", s))?;
+    Ok((ext, cap))
+}
+
+/// Parses a `--max-filename-length`, which must leave room for the hash suffix
+fn parse_filename_length(s: &str) -> Result<usize, String> {
+    let len: usize = s.trim().parse().map_err(|_| format!("expected a byte count, got '{}'", s))?;
+    if len < schema::MIN_MAX_FILENAME_LENGTH {
+        return Err(format!(
+            "must be at least {} bytes to fit the hash suffix",
+            schema::MIN_MAX_FILENAME_LENGTH
+        ));
+    }
+    Ok(len)
+}
+
+/// Parses a MIME type glob such as `image/*`
+fn parse_mime_pattern(s: &str) -> Result<glob::Pattern, String> {
+    glob::Pattern::new(s.trim()).map_err(|e| format!("invalid MIME pattern '{}': {}", s, e))
+}
+
+/// Normalizes a file extension: no leading dot, lowercase
+fn parse_extension(s: &str) -> Result<String, String> {
+    let ext = s.trim().trim_start_matches('.').to_lowercase();
+    if ext.is_empty() {
+        return Err(format!("missing extension in '{}'", s));
+    }
+    Ok(ext)
+}
+
+/// Checks that an `--include`/`--exclude` pattern compiles
+fn parse_path_glob(s: &str) -> Result<String, String> {
+    schema::path_glob(s)?;
+    Ok(s.trim().to_string())
+}
+
+/// Parses a `KEY=VALUE` environment condition
+fn parse_env_condition(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", s)),
+    }
+}
+
+/// Checks a `--run-if-env` condition, printing the skip reason when it doesn't match
+fn env_condition_met(condition: &(String, String)) -> bool {
+    let (key, expected) = condition;
+    match env::var(key) {
+        Ok(actual) if &actual == expected => {
+            output::print_notice(format!("Run condition met: {}={}", key, expected));
+            true
+        }
+        Ok(actual) => {
+            output::print_notice(format!(
+                "Skipping sync: {} is '{}', expected '{}'",
+                key, actual, expected
+            ));
+            false
+        }
+        Err(_) => {
+            output::print_notice(format!("Skipping sync: {} is not set, expected '{}'", key, expected));
+            false
+        }
+    }
+}
+
+async fn get_config_dir() -> Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .context("Failed to get config directory")?
+        .join("cli-file-sync"))
+}
+
+/// Compare two asset lists and return only the changed or new assets
+fn get_changed_assets(old_assets: &[DrupalFileAsset], new_assets: &[DrupalFileAsset]) -> Vec<DrupalFileAsset> {
+    let mut changed = Vec::new();
+    let old_map: HashMap<_, _> = old_assets
+        .iter()
+        .map(|asset| (asset.id.clone(), asset))
+        .collect();
+
+    for new_asset in new_assets {
+        match old_map.get(&new_asset.id) {
+            Some(old_asset) => {
+                if old_asset.changed != new_asset.changed {
+                    changed.push(new_asset.clone());
+                }
+            }
+            None => {
+                changed.push(new_asset.clone());
+            }
+        }
+    }
+
+    changed
+}
+
+/// Manifest of the last clean run, the baseline of an incremental sync
+const BASELINE_FILE: &str = ".sync-baseline.json";
+
+/// Keeps the assets that are new or changed since `previous`, plus unchanged
+/// ones missing from a destination, and prints the breakdown
+fn incremental_assets(
+    previous: &[DrupalFileAsset],
+    assets: Vec<DrupalFileAsset>,
+    destinations: &[PathBuf],
+    config: &DownloadConfig,
+) -> Vec<DrupalFileAsset> {
+    let previous_ids: HashSet<&str> = previous.iter().map(|asset| asset.id.as_str()).collect();
+    let changed: HashSet<String> = get_changed_assets(previous, &assets)
+        .into_iter()
+        .map(|asset| asset.id)
+        .collect();
+    let new = changed.iter().filter(|id| !previous_ids.contains(id.as_str())).count();
+    let total = assets.len();
+
+    let present = |asset: &DrupalFileAsset| {
+        destinations
+            .iter()
+            .all(|destination| Downloader::local_file_path(asset, config, destination).exists())
+    };
+    let mut missing = 0;
+    let selected: Vec<DrupalFileAsset> = assets
+        .into_iter()
+        .filter(|asset| {
+            if changed.contains(&asset.id) {
+                return true;
+            }
+            let keep = !present(asset);
+            if keep {
+                missing += 1;
+            }
+            keep
+        })
+        .collect();
+
+    status!(
+        "Incremental sync: {} new, {} changed, {} unchanged",
+        new,
+        changed.len() - new,
+        total - changed.len()
+    );
+    if missing > 0 {
+        status!("Fetching {} unchanged assets missing locally", missing);
+    }
+    selected
+}
+
+/// Lists or deletes the files earlier syncs wrote to `destination` that
+/// the manifest no longer has, then records what this run left there.
+/// A dry run only lists them.
+async fn prune_destination(
+    assets: &[DrupalFileAsset],
+    config: &DownloadConfig,
+    destination: &Path,
+    delete: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let mut managed = ManagedFiles::open(destination).await?;
+    let expected: HashSet<String> = assets
+        .iter()
+        .filter_map(|asset| managed.relative(&Downloader::local_file_path(asset, config, destination)))
+        .collect();
+
+    let orphans = managed.orphans(&expected);
+    if !orphans.is_empty() {
+        if delete && !dry_run {
+            let deleted = managed.delete(&orphans).await?;
+            status!("Deleted {} files no longer in the metadata from {}", deleted, destination.display());
+        } else {
+            status!("Would delete {} files no longer in the metadata (pass --delete to remove them):", orphans.len());
+            for orphan in &orphans {
+                status!("  {}", destination.join(orphan).display());
+            }
+        }
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+    managed.track(&expected);
+    managed.save().await
+}
+
+/// Streams the metadata body into `part_path`, resuming from an existing
+/// partial file with a Range request when one is present
+async fn fetch_metadata_to_part(
+    source: &str,
+    part_path: &Path,
+    credentials: &Credentials,
+    metadata_request: &MetadataRequest,
+    min_tls_version: TlsVersion,
+) -> Result<Option<String>> {
+    use tokio::io::AsyncWriteExt;
+
+    let client = min_tls_version.client()?;
+
+    // A query response isn't guaranteed to be stable, so only resume GETs
+    if metadata_request.method == MetadataMethod::Post && part_path.exists() {
+        tokio::fs::remove_file(part_path).await?;
+    }
+
+    loop {
+        let existing = tokio::fs::metadata(part_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        let mut request = credentials.apply(metadata_request.build(&client, source));
+
+        if existing > 0 {
+            // The partial file holds decoded bytes, so ranges must address
+            // the uncompressed representation
+            status!("Resuming metadata download from byte {}", existing);
+            request = request
+                .header(reqwest::header::RANGE, format!("bytes={}-", existing))
+                .header(reqwest::header::ACCEPT_ENCODING, "identity");
+        } else {
+            request = request.header(reqwest::header::ACCEPT_ENCODING, "gzip, br, zstd");
+        }
+
+        let mut response = request
+            .send()
+            .await
+            .map_err(|e| min_tls_version.explain(e))
+            .context("Failed to send HTTP request")?;
+        status!("Response status: {}", response.status());
+
+        if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            // The partial file no longer lines up with the source, start over
+            status!("Partial metadata is stale, restarting download");
+            tokio::fs::remove_file(part_path).await?;
+            continue;
+        }
+
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Failed to download metadata: HTTP {} {}",
+                response.status().as_u16(),
+                response.status().as_str()
+            ));
+        }
+
+        // A plain 200 means the server ignored the Range header
+        let append = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(part_path)
+            .await
+            .context(format!("Failed to open {}", part_path.display()))?;
+
+        let encoding = response
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        let mut writer: Box<dyn tokio::io::AsyncWrite + Unpin + Send> = match encoding.as_str() {
+            "" | "identity" => Box::new(file),
+            "gzip" | "x-gzip" => Box::new(async_compression::tokio::write::GzipDecoder::new(file)),
+            "br" => Box::new(async_compression::tokio::write::BrotliDecoder::new(file)),
+            "zstd" => Box::new(async_compression::tokio::write::ZstdDecoder::new(file)),
+            other => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported metadata Content-Encoding: {}",
+                    other
+                ))
+            }
+        };
+
+        let mut wire_bytes = 0u64;
+        while let Some(chunk) = response.chunk().await.context("Failed to read response body")? {
+            wire_bytes += chunk.len() as u64;
+            writer
+                .write_all(&chunk)
+                .await
+                .context("Failed to write metadata")?;
+        }
+        // Shutting down finishes the decoder and flushes the file
+        writer.shutdown().await.context("Failed to write metadata")?;
+
+        if encoding.is_empty() || encoding == "identity" {
+            status!("Metadata transfer: {} bytes", wire_bytes);
+        } else {
+            let decoded = tokio::fs::metadata(part_path)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+            status!(
+                "Metadata transfer: {} bytes on the wire ({}), {} bytes decompressed",
+                wire_bytes,
+                encoding,
+                decoded
+            );
+        }
+
+        return Ok(content_type(&response));
+    }
+}
+
+/// A response's lowercase Content-Type without parameters
+fn content_type(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+}
+
+/// Compression formats recognized from a manifest's file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ManifestCompression {
+    Gzip,
+    Zstd,
+    Brotli,
+}
+
+impl ManifestCompression {
+    /// Detects the format from a path or URL, ignoring any query string
+    fn from_name(name: &str) -> Option<Self> {
+        let path = name.split(['?', '#']).next().unwrap_or(name).to_ascii_lowercase();
+        if path.ends_with(".gz") {
+            Some(Self::Gzip)
+        } else if path.ends_with(".zst") {
+            Some(Self::Zstd)
+        } else if path.ends_with(".br") {
+            Some(Self::Brotli)
+        } else {
+            None
+        }
+    }
+
+    async fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZstdDecoder};
+        use tokio::io::AsyncReadExt;
+
+        let mut decoded = Vec::new();
+        match self {
+            Self::Gzip => {
+                let mut decoder = GzipDecoder::new(bytes);
+                decoder.multiple_members(true);
+                decoder.read_to_end(&mut decoded).await?
+            }
+            Self::Zstd => ZstdDecoder::new(bytes).read_to_end(&mut decoded).await?,
+            Self::Brotli => BrotliDecoder::new(bytes).read_to_end(&mut decoded).await?,
+        };
+        Ok(decoded)
+    }
+}
+
+/// Turns raw manifest bytes into text, decompressing them when `name` has a
+/// compression extension. Bytes that already look like JSON are left alone,
+/// since servers often decode `.json.gz` files via Content-Encoding.
+/// Returns the text and whether it was decompressed.
+async fn decode_manifest(bytes: Vec<u8>, name: &str) -> Result<(String, bool)> {
+    let looks_like_json = bytes
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .map_or(true, |b| *b == b'{' || *b == b'[');
+
+    let (bytes, decompressed) = match ManifestCompression::from_name(name) {
+        Some(compression) if !looks_like_json => {
+            let decoded = compression
+                .decompress(&bytes)
+                .await
+                .context(format!("Failed to decompress manifest {} as {:?}", name, compression))?;
+            (decoded, true)
+        }
+        _ => (bytes, false),
+    };
+
+    let content = String::from_utf8(bytes).context(format!("Manifest {} is not valid UTF-8", name))?;
+    Ok((content, decompressed))
+}
+
+/// Reads a local manifest, decompressing `.gz`/`.zst`/`.br` files
+async fn read_manifest_file(path: &Path) -> Result<String> {
+    let bytes = fs::read(path)
+        .await
+        .context(format!("Failed to read manifest {}", path.display()))?;
+    Ok(decode_manifest(bytes, &path.to_string_lossy()).await?.0)
+}
+
+/// Reads a manifest from a URL or local path without saving it anywhere.
+/// Returns the content and, for a URL, the response's Content-Type.
+async fn read_manifest_source(
+    source: &str,
+    credentials: &Credentials,
+    min_tls_version: TlsVersion,
+) -> Result<(String, Option<String>)> {
+    let (bytes, content_type) = if source.starts_with("http://") || source.starts_with("https://") {
+        let response = credentials
+            .apply(min_tls_version.client()?.get(source))
+            .send()
+            .await
+            .map_err(|e| min_tls_version.explain(e))
+            .context("Failed to send HTTP request")?;
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!(
+                "Failed to download metadata: HTTP {}",
+                response.status()
+            ));
+        }
+        let content_type = content_type(&response);
+        let bytes = response.bytes().await.context("Failed to read response body")?.to_vec();
+        (bytes, content_type)
+    } else {
+        let bytes = fs::read(source)
+            .await
+            .context(format!("Failed to read manifest {}", source))?;
+        (bytes, None)
+    };
+    Ok((decode_manifest(bytes, source).await?.0, content_type))
+}
+
+/// Reads a manifest the way sync does: normalized, with every page of a
+/// paginated URL source appended
+async fn read_paged_manifest(source: &str, credentials: &Credentials, metadata_request: &MetadataRequest) -> Result<String> {
+    let (content, content_type) = read_manifest_source(source, credentials, TlsVersion::default()).await?;
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let (content, _) = follow_next_pages(
+            content,
+            content_type.as_deref(),
+            source,
+            credentials,
+            metadata_request,
+            TlsVersion::default(),
+            &FieldMap::default(),
+        )
+        .await?;
+        Ok(content)
+    } else {
+        Ok(normalize_manifest(content, &FieldMap::default(), source, None)?.0)
+    }
+}
+
+/// Validates a manifest and reports every problem; fails when any is found
+async fn handle_validate_command(
+    source: &str,
+    credentials: &Credentials,
+    metadata_request: &MetadataRequest,
+    max_filename_length: usize,
+    output: OutputFormat,
+) -> Result<()> {
+    let content = read_paged_manifest(source, credentials, metadata_request).await?;
+    let (assets, problems) = match serde_json::from_str::<DrupalFileAssetsResponse>(&content) {
+        Ok(response) => {
+            let assets = response.into_vec();
+            let problems = schema::check_manifest(&assets, max_filename_length);
+            (assets.len(), problems)
+        }
+        Err(e) => (
+            0,
+            vec![schema::ManifestProblem {
+                id: None,
+                field: None,
+                message: format!("Not a valid manifest (wrapper or array): {}", e),
+            }],
+        ),
+    };
+
+    match output {
+        OutputFormat::Json => {
+            let report = serde_json::json!({
+                "source": source,
+                "valid": problems.is_empty(),
+                "assets": assets,
+                "problems": problems,
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        OutputFormat::Text => {
+            println!("Checked {} assets in {}", assets, source);
+            for problem in &problems {
+                let location = match (&problem.id, &problem.field) {
+                    (Some(id), Some(field)) => format!("asset {} {}: ", id, field),
+                    (Some(id), None) => format!("asset {}: ", id),
+                    (None, Some(field)) => format!("{}: ", field),
+                    (None, None) => String::new(),
+                };
+                println!("  {}{}", location, problem.message);
+            }
+            if problems.is_empty() {
+                println!("PASS: manifest is valid");
+            } else {
+                println!("FAIL: {} problems found", problems.len());
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Manifest validation failed with {} problems", problems.len()))
+    }
+}
+
+/// Loads a previously saved manifest (wrapper or bare array) from disk
+async fn load_manifest(path: &Path) -> Result<Vec<DrupalFileAsset>> {
+    let content = read_manifest_file(path).await?;
+    let response: DrupalFileAssetsResponse = serde_json::from_str(&content)
+        .context(format!("Failed to parse manifest {}", path.display()))?;
+    Ok(response.into_vec())
+}
+
+/// Normalizes a metadata response and follows its next-page links, appending
+/// each page's files to the first page's. Links are read from each page as
+/// received, before a field map can drop them. Later pages are plain GETs,
+/// sent with `credentials` only on the source's own origin. Returns the
+/// combined manifest and whether it differs from the first page as received.
+async fn follow_next_pages(
+    first: String,
+    content_type: Option<&str>,
+    source: &str,
+    credentials: &Credentials,
+    metadata_request: &MetadataRequest,
+    min_tls_version: TlsVersion,
+    field_map: &FieldMap,
+) -> Result<(String, bool)> {
+    let pointer = &metadata_request.next_page_pointer;
+    let mut next = serde_json::from_str::<serde_json::Value>(&first)
+        .ok()
+        .and_then(|page| next_page_link(&page, pointer, source));
+    let (first, normalized) = normalize_manifest(first, field_map, source, content_type)?;
+    if next.is_none() {
+        return Ok((first, normalized));
+    }
+    let mut combined: serde_json::Value = serde_json::from_str(&first)
+        .context(format!("Failed to parse metadata page {}", source))?;
+
+    let mut visited = HashSet::from([source.to_string()]);
+    let mut pages = 1;
+    while let Some(url) = next {
+        if !visited.insert(url.clone()) {
+            return Err(anyhow::anyhow!("Metadata page {} links back to an earlier page", url));
+        }
+        if pages >= metadata_request.max_pages {
+            return Err(anyhow::anyhow!(
+                "Metadata has more than {} pages (--max-pages); next page would be {}",
+                metadata_request.max_pages,
+                url
+            ));
+        }
+        status!("Fetching metadata page {} from {}", pages + 1, url);
+        // The link comes from the response, so it mustn't carry credentials elsewhere
+        let page_credentials = if same_origin(&url, source) {
+            credentials.clone()
+        } else {
+            log::warn!("Metadata page {} is on another host; fetching it without credentials", url);
+            Credentials::default()
+        };
+        let (content, content_type) = read_manifest_source(&url, &page_credentials, min_tls_version).await?;
+        next = serde_json::from_str::<serde_json::Value>(&content)
+            .ok()
+            .and_then(|page| next_page_link(&page, pointer, &url));
+        let (content, _) = normalize_manifest(content, field_map, &url, content_type.as_deref())?;
+        let page: serde_json::Value = serde_json::from_str(&content)
+            .context(format!("Failed to parse metadata page {}", url))?;
+
+        let files = match page {
+            serde_json::Value::Object(mut object) => object.remove("files"),
+            array => Some(array),
+        };
+        let target = match &mut combined {
+            serde_json::Value::Object(object) => object.get_mut("files"),
+            array => Some(array),
+        };
+        match (target, files) {
+            (Some(serde_json::Value::Array(target)), Some(serde_json::Value::Array(files))) => target.extend(files),
+            _ => return Err(anyhow::anyhow!("Metadata page {} has no files array", url)),
+        }
+        pages += 1;
+    }
+
+    status!("Read {} metadata pages", pages);
+    Ok((serde_json::to_string(&combined)?, true))
+}
+
+/// Whether two URLs share a scheme, host and port
+fn same_origin(a: &str, b: &str) -> bool {
+    match (url::Url::parse(a), url::Url::parse(b)) {
+        (Ok(a), Ok(b)) => a.origin() == b.origin(),
+        _ => false,
+    }
+}
+
+/// The next-page URL at `pointer`, resolved against the page it came from
+fn next_page_link(page: &serde_json::Value, pointer: &str, page_url: &str) -> Option<String> {
+    let link = match page.pointer(pointer)? {
+        serde_json::Value::String(link) => link.as_str(),
+        serde_json::Value::Object(object) => object.get("href")?.as_str()?,
+        _ => return None,
+    };
+    if link.is_empty() {
+        return None;
+    }
+    url::Url::parse(page_url)
+        .and_then(|base| base.join(link))
+        .map(String::from)
+        .ok()
+}
+
+/// Brings a manifest into the Drupal JSON form: YAML is re-encoded, CSV rows
+/// and URL lists become asset arrays, and a field map renames a custom
+/// manifest's keys. `content_type` is the response's, for URL sources.
+/// Returns the content and whether it was rewritten.
+fn normalize_manifest(
+    content: String,
+    field_map: &FieldMap,
+    source: &str,
+    content_type: Option<&str>,
+) -> Result<(String, bool)> {
+    if schema::is_csv(source) {
+        let files = schema::csv_to_json(&content)
+            .map_err(anyhow::Error::msg)
+            .context(format!("Failed to read CSV manifest {}", source))?;
+        // Applied even without a map, to fill uri and mime from the other columns
+        let files = field_map
+            .apply(&files)
+            .context(format!("Failed to apply --field-map to {}", source))?;
+        status!("Read CSV manifest {}", source);
+        return Ok((files, true));
+    }
+    // YAML carries the same wrapper or array as JSON
+    let (content, converted) = if schema::is_yaml(source, content_type) {
+        let json = schema::yaml_to_json(&content)
+            .map_err(anyhow::Error::msg)
+            .context(format!("Failed to parse YAML manifest {}", source))?;
+        status!("Read YAML manifest {}", source);
+        (json, true)
+    } else {
+        (content, false)
+    };
+    if !converted && schema::is_url_list(source, content_type) {
+        let assets = schema::parse_url_list(&content);
+        status!("Read {} URLs from the list {}", assets.len(), source);
+        return Ok((serde_json::to_string(&assets)?, true));
+    }
+    if field_map.is_empty() {
+        return Ok((content, converted));
+    }
+    let mapped = field_map
+        .apply(&content)
+        .context(format!("Failed to apply --field-map to {}", source))?;
+    status!("Mapped manifest fields with --field-map");
+    Ok((mapped, true))
+}
+
+/// Whether a local manifest path is a glob pattern rather than a single file
+fn is_manifest_glob(source: &str) -> bool {
+    source.contains(['*', '?', '['])
+}
+
+/// Reads every local manifest shard matching `pattern` and merges them in
+/// path order. An id already seen in an earlier shard is dropped.
+async fn read_manifest_glob(pattern: &str, field_map: &FieldMap) -> Result<Manifest> {
+    let mut paths = glob::glob(pattern)
+        .context(format!("Invalid manifest pattern {}", pattern))?
+        .collect::<Result<Vec<PathBuf>, _>>()
+        .context(format!("Failed to expand manifest pattern {}", pattern))?;
+    paths.sort();
+    if paths.is_empty() {
+        return Err(anyhow::anyhow!("No manifest files match {}", pattern));
+    }
+    status!("Pattern {} matched {} manifest files", pattern, paths.len());
+
+    let mut manifest = Manifest::default();
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+    for path in &paths {
+        let (content, _) =
+            normalize_manifest(read_manifest_file(path).await?, field_map, &path.display().to_string(), None)?;
+        let shard: DrupalFileAssetsResponse = serde_json::from_str(&content)
+            .context(format!("Failed to parse manifest {}", path.display()))?;
+        let files = match shard {
+            DrupalFileAssetsResponse::Wrapper(wrapper) => {
+                // The merged manifest is only as fresh as its oldest shard
+                manifest.generated = Some(match manifest.generated {
+                    Some(generated) => generated.min(wrapper.generated),
+                    None => wrapper.generated,
+                });
+                for directory in wrapper.directories {
+                    if !manifest.directories.contains(&directory) {
+                        manifest.directories.push(directory);
+                    }
+                }
+                wrapper.files
+            }
+            DrupalFileAssetsResponse::Array(files) => files,
+        };
+        status!("Read {} files from {}", files.len(), path.display());
+        for asset in files {
+            if seen.insert(asset.id.clone()) {
+                manifest.files.push(asset);
+            } else {
+                duplicates += 1;
+            }
+        }
+    }
+
+    if duplicates > 0 {
+        status!("Dropped {} assets repeated across shards", duplicates);
+    }
+    status!("Merged {} files from {} shards", manifest.files.len(), paths.len());
+    Ok(manifest)
+}
+
+/// Re-serializes a manifest with files sorted by id, object keys sorted and
+/// pretty-printing, so equivalent manifests are saved byte-for-byte equal
+async fn save_canonical_manifest(content: &str, path: &Path) -> Result<()> {
+    let mut value: serde_json::Value =
+        serde_json::from_str(content).context("Failed to parse manifest for canonicalization")?;
+    let files = match &mut value {
+        serde_json::Value::Array(files) => Some(files),
+        serde_json::Value::Object(wrapper) => match wrapper.get_mut("files") {
+            Some(serde_json::Value::Array(files)) => Some(files),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(files) = files {
+        files.sort_by(|a, b| {
+            let id = |file: &serde_json::Value| match &file["id"] {
+                serde_json::Value::String(id) => id.clone(),
+                other => other.to_string(),
+            };
+            id(a).cmp(&id(b))
+        });
+    }
+    // serde_json keeps object keys in sorted order
+    let canonical = serde_json::to_string_pretty(&value)? + "\n";
+    fs::write(path, canonical)
+        .await
+        .context(format!("Failed to write {}", path.display()))?;
+    status!("Saved canonical manifest to {}", path.display());
+    Ok(())
+}
+
+async fn download_metadata(
+    source: &str,
+    destination: &Path,
+    force: bool,
+    credentials: &Credentials,
+    metadata_request: &MetadataRequest,
+    min_tls_version: TlsVersion,
+    canonicalize: bool,
+    dir_mode: Option<u32>,
+    field_map: &FieldMap,
+) -> Result<Manifest> {
+    // Create destination directory if it doesn't exist
+    status!("Ensuring destination directory exists: {}", destination.display());
+    if !destination.exists() {
+        downloader::create_dir_all_with_mode(destination, dir_mode).await.context(format!("Failed to create directory: {}", destination.display()))?;
+    }
+
+    let metadata_path = destination.join("assets.json");
+    status!("Will save metadata to: {}", metadata_path.display());
+    
+    // First, always download or read the content
+    let content = if source.starts_with("http://") || source.starts_with("https://") {
+        match metadata_request.method {
+            MetadataMethod::Get => status!("Downloading metadata from {}", source),
+            MetadataMethod::Post => status!("Querying metadata from {} (POST)", source),
+        }
+        status!("This may take a while for large files...");
+        
+        let part_path = destination.join("assets.json.part");
+        let content_type =
+            fetch_metadata_to_part(source, &part_path, credentials, metadata_request, min_tls_version).await?;
+
+        status!("Saving content to file: {}", metadata_path.display());
+        tokio::fs::rename(&part_path, &metadata_path)
+            .await
+            .context(format!("Failed to move {} into place", part_path.display()))?;
+
+        let bytes = tokio::fs::read(&metadata_path)
+            .await
+            .context(format!("Failed to read {}", metadata_path.display()))?;
+        let compressed_len = bytes.len();
+        let (content, decompressed) = decode_manifest(bytes, source).await?;
+        if decompressed {
+            status!("Decompressed manifest: {} bytes -> {} bytes", compressed_len, content.len());
+        }
+        let (content, rewritten) = follow_next_pages(
+            content,
+            content_type.as_deref(),
+            source,
+            credentials,
+            metadata_request,
+            min_tls_version,
+            field_map,
+        )
+        .await?;
+        if decompressed || rewritten {
+            // Keep the saved copy readable by later runs and mirrors
+            tokio::fs::write(&metadata_path, &content)
+                .await
+                .context(format!("Failed to write {}", metadata_path.display()))?;
+        }
+        status!("Download complete! Content length: {} bytes", content.len());
+        if content.len() > 0 {
+            log::debug!("Content preview: {}", content.chars().take(200).collect::<String>());
+        } else {
+            log::warn!("Downloaded content is empty!");
+        }
+        
+        // Verify the file was written
+        if metadata_path.exists() {
+            log::debug!("Successfully wrote metadata file");
+            let file_size = tokio::fs::metadata(&metadata_path)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+            log::debug!("File size: {} bytes", file_size);
+        } else {
+            log::warn!("File was not created!");
+        }
+        
+        content
+    } else if is_manifest_glob(source) {
+        let manifest = read_manifest_glob(source, field_map).await?;
+        if canonicalize {
+            let merged = serde_json::to_string(&manifest.files)?;
+            save_canonical_manifest(&merged, &metadata_path).await?;
+        }
+        return Ok(manifest);
+    } else {
+        status!("Reading local file {}", source);
+        let bytes = fs::read(source)
+            .await
+            .context(format!("Failed to read manifest {}", source))?;
+        let compressed_len = bytes.len();
+        let (content, decompressed) = decode_manifest(bytes, source).await?;
+        if decompressed {
+            status!("Decompressed manifest: {} bytes -> {} bytes", compressed_len, content.len());
+        }
+        normalize_manifest(content, field_map, source, None)?.0
+    };
+
+    if canonicalize {
+        save_canonical_manifest(&content, &metadata_path).await?;
+    }
+
+    // Now try parsing the content
+    status!("Parsing metadata from {}...", metadata_path.display());
+    
+    // Try parsing as raw value first to understand the structure
+    match serde_json::from_str::<serde_json::Value>(&content) {
+        Ok(value) => {
+            log::debug!("Successfully parsed as JSON. Root structure: {}", 
+                if value.is_object() { "object" }
+                else if value.is_array() { "array" }
+                else { "other" }
+            );
+            
+            if let Some(obj) = value.as_object() {
+                log::debug!("Available fields at root: {:?}", obj.keys().collect::<Vec<_>>());
+                if let Some(files) = obj.get("files") {
+                    if let Some(files_arr) = files.as_array() {
+                        log::debug!("Found files array with {} items", files_arr.len());
+                    } else {
+                        log::debug!("'files' field is not an array");
+                    }
+                }
+            }
+        }
+        Err(e) => log::debug!("Failed to parse as raw JSON: {}", e),
+    }
+    
+    // Try parsing as a wrapper
+    match serde_json::from_str::<DrupalFileAssetsWrapper>(&content) {
+        Ok(wrapper) => {
+            status!("Successfully parsed as wrapper with {} files", wrapper.files.len());
+            Ok(Manifest {
+                files: wrapper.files,
+                directories: wrapper.directories,
+                generated: Some(wrapper.generated),
+            })
+        }
+        Err(wrapper_err) => {
+            // If that fails, try parsing as an array
+            match serde_json::from_str::<Vec<DrupalFileAsset>>(&content) {
+                Ok(assets) => {
+                    status!("Successfully parsed as array with {} files", assets.len());
+                    Ok(Manifest {
+                        files: assets,
+                        directories: Vec::new(),
+                        generated: None,
+                    })
+                }
+                Err(array_err) => {
+                    log::debug!("Failed to parse as wrapper: {}", wrapper_err);
+                    log::debug!("Failed to parse as array: {}", array_err);
+                    Err(anyhow::anyhow!("Failed to parse metadata as JSON: {}", wrapper_err))
+                }
+            }
+        }
+    }
+}
+
+/// Settings of a sync run beyond how files are downloaded, which live in
+/// `DownloadConfig`, and where its reports go
+struct SyncOptions {
+    assets_metadata: String,
+    destinations: Vec<PathBuf>,
+    /// Credentials for the metadata source; downloads use the config's
+    source_credentials: Credentials,
+    metadata_request: MetadataRequest,
+    field_map: FieldMap,
+    patch_manifests: Vec<String>,
+    max_manifest_age: Option<u64>,
+    canonicalize_manifest: bool,
+    continue_from_id: Option<String>,
+    diff_base: Option<PathBuf>,
+    include_tags: Vec<String>,
+    exclude_tags: Vec<String>,
+    include_mime: Vec<glob::Pattern>,
+    exclude_ext: Vec<String>,
+    include_paths: Vec<String>,
+    exclude_paths: Vec<String>,
+    use_queue: bool,
+    reset_queue: bool,
+    /// ETag cache file; defaults to `STATE_FILE` in the first destination
+    etag_cache: Option<PathBuf>,
+    no_etag_cache: bool,
+    verify_after_sync: bool,
+    verify_sample: Option<VerifySample>,
+    post_hooks: Vec<PostHook>,
+    post_hook_timeout: u64,
+    fail_on_hook_error: bool,
+    preserve_empty_dirs: bool,
+    delete: bool,
+    dry_run_diff: bool,
+    check_only: bool,
+    /// Profile named in every report and failure record
+    config_id: String,
+}
+
+/// Reports a sync run writes besides its summary
+struct ReportOptions {
+    report_dir: Option<PathBuf>,
+    max_reports: u32,
+    retention_days: Option<u32>,
+    name: ReportNameTemplate,
+    formats: Vec<ReportFormat>,
+    /// Base path of the failure CSV/JSON; defaults to `failed_downloads` in the first destination
+    failures_report: Option<PathBuf>,
+    dedupe_report: Option<PathBuf>,
+    sqlite: Option<PathBuf>,
+}
+
+async fn handle_sync_command(options: &SyncOptions, reports: &ReportOptions, mut config: DownloadConfig) -> Result<()> {
+    let started = std::time::Instant::now();
+    // Ties together every record, report and summary from this run
+    let sync_id = uuid::Uuid::new_v4().to_string();
+    status!("Sync run {}", sync_id);
+
+    // Get the current working directory
+    let current_dir = std::env::current_dir()?;
+    
+    // If a destination is just a name (like "downloads"), make it relative to current directory
+    let destinations: Vec<PathBuf> = options
+        .destinations
+        .iter()
+        .map(|destination| {
+            if destination.is_absolute() {
+                destination.to_path_buf()
+            } else {
+                current_dir.join(destination)
+            }
+        })
+        .collect();
+    let destination = &destinations[0];
+
+    // Source credentials authenticate the metadata, download credentials the files
+    let metadata_credentials = &options.source_credentials;
+
+    // The manifest of the last clean run is the baseline for an incremental
+    // sync. Leftover failures mean that run didn't finish cleanly.
+    let failures_base = reports
+        .failures_report
+        .clone()
+        .unwrap_or_else(|| destination.join("failed_downloads"));
+    let previous_metadata = destination.join(BASELINE_FILE);
+    let previous_assets = if config.force
+        || options.diff_base.is_some()
+        || options.dry_run_diff
+        || options.check_only
+        || !previous_metadata.exists() {
+        None
+    } else if failures_base.with_extension("json").exists() {
+        status!("Previous run left failures in {}.json; checking every asset", failures_base.display());
+        None
+    } else {
+        match load_manifest(&previous_metadata).await {
+            Ok(previous) => Some(previous),
+            Err(e) => {
+                log::warn!("ignoring previous {}: {:#}", previous_metadata.display(), e);
+                None
+            }
+        }
+    };
+
+    // A dry run or pre-flight check keeps its copy of the metadata out of the
+    // destination, so the next real run still diffs against the last synced manifest
+    let preview = config.dry_run || options.check_only;
+    let metadata_dir = if preview {
+        std::env::temp_dir().join(format!("cli-file-sync-{}", sync_id))
+    } else {
+        destination.clone()
+    };
+
+    // Download or read metadata file
+    let manifest = download_metadata(
+        &options.assets_metadata,
+        &metadata_dir,
+        config.force,
+        metadata_credentials,
+        &options.metadata_request,
+        config.min_tls_version,
+        options.canonicalize_manifest,
+        config.dir_mode,
+        &options.field_map,
+    )
+    .await;
+    if preview {
+        let _ = fs::remove_dir_all(&metadata_dir).await;
+    }
+    let manifest = manifest?;
+
+    if let Some(max_age) = options.max_manifest_age {
+        match manifest.generated {
+            Some(generated) => {
+                let age = chrono::Utc::now().timestamp() - generated;
+                if age > max_age as i64 {
+                    return Err(anyhow::anyhow!(
+                        "Manifest is stale: generated {}s ago at {}, older than --max-manifest-age of {}s",
+                        age,
+                        chrono::DateTime::from_timestamp(generated, 0)
+                            .map(|time| time.to_rfc3339())
+                            .unwrap_or_else(|| generated.to_string()),
+                        max_age
+                    ));
+                }
+                status!("Manifest generated {}s ago, within --max-manifest-age", age);
+            }
+            None => log::warn!("manifest has no generated timestamp, skipping --max-manifest-age check"),
+        }
+    }
+
+    let mut assets = manifest.files;
+
+    for patch_source in &options.patch_manifests {
+        let (content, _) = read_manifest_source(patch_source, metadata_credentials, config.min_tls_version).await?;
+        let patch: ManifestPatch = serde_json::from_str(&content)
+            .context(format!("Failed to parse patch manifest {}", patch_source))?;
+        let (patched, stats) = patch.apply(assets);
+        assets = patched;
+        status!(
+            "Applied patch {}: {} added, {} updated, {} removed",
+            patch_source, stats.added, stats.updated, stats.removed
+        );
+    }
+
+    // Mirror the saved metadata into every additional destination, unless nothing is written
+    let metadata_path = destination.join("assets.json");
+    let mirrors: &[PathBuf] = if preview { &[] } else { &destinations[1..] };
+    for mirror in mirrors {
+        downloader::create_dir_all_with_mode(mirror, config.dir_mode)
+            .await
+            .context(format!("Failed to create directory: {}", mirror.display()))?;
+        if metadata_path.exists() {
+            fs::copy(&metadata_path, mirror.join("assets.json"))
+                .await
+                .context(format!("Failed to copy metadata to {}", mirror.display()))?;
+        }
+    }
+
+    // Report every manifest problem in one pass; invalid assets still go
+    // through the sync so this doesn't change which files get fetched
+    let invalid = schema::validate_assets(&assets);
+    if !invalid.is_empty() {
+        status!("Manifest validation found problems in {} assets:", invalid.len());
+        for asset_errors in &invalid {
+            for error in &asset_errors.errors {
+                status!("  asset {}: {}", asset_errors.id, error);
+            }
+        }
+    }
+
+    // Everything in the manifest, before any narrowing, decides what is an orphan
+    let manifest_assets = assets.clone();
+    let assets = select_assets(
+        assets,
+        options.continue_from_id.as_deref(),
+        options.diff_base.as_deref(),
+        &options.include_tags,
+        &options.exclude_tags,
+        &options.include_mime,
+        &options.exclude_ext,
+        &options.include_paths,
+        &options.exclude_paths,
+    )
+    .await?;
+    let etag_cache = match &options.etag_cache {
+        Some(path) => {
+            let cache = EtagCache::load(path).await?;
+            status!("Loaded {} cached ETags from {}", cache.len().await, path.display());
+            Some(cache)
+        }
+        None if options.no_etag_cache => None,
+        None => Some(EtagCache::load(&destination.join(STATE_FILE)).await?),
+    };
+
+    // Resolved here, against the first destination and the working directory
+    config.etag_cache = etag_cache.clone();
+    config.cas_dir = config.cas_dir.map(|dir| current_dir.join(dir));
+
+    let assets = match &previous_assets {
+        Some(previous) => incremental_assets(previous, assets, &destinations, &config),
+        None => assets,
+    };
+
+    let mut queue = None;
+    // A change plan must not claim queue items
+    let assets = if (options.use_queue || options.reset_queue) && !options.dry_run_diff && !preview {
+        let queue_path = destination.join("queue.json");
+        if options.reset_queue {
+            WorkQueue::reset(&queue_path).await?;
+        }
+        let mut work_queue = WorkQueue::open(&queue_path).await?;
+        let queued = work_queue.enqueue(&assets);
+        let claimed = work_queue.claim();
+        work_queue.save().await?;
+        status!(
+            "Queue {}: {} newly queued, {} claimed for this run",
+            queue_path.display(),
+            queued,
+            claimed.len()
+        );
+        queue = Some(work_queue);
+        claimed
+    } else {
+        assets
+    };
+
+    status!("Found {} assets to process", assets.len());
+
+    if options.dry_run_diff {
+        return print_change_plan(&Downloader::new(config), &assets, destination).await;
+    }
+
+    if options.check_only {
+        return run_precheck(&Downloader::new(config), &assets, reports.report_dir.as_deref().unwrap_or(destination)).await;
+    }
+
+    if config.dry_run {
+        println!("Dry run: {} assets to check against {}", assets.len(), destination.display());
+        let downloader = Downloader::new(config.clone());
+        downloader.download_files(&assets, destinations.clone()).await?;
+        print_plan(&downloader.planned_changes().await);
+        for failed in downloader.failed_downloads().await {
+            println!("  {:<12} {} ({})", "would-fail", failed.filename, failed.error);
+        }
+        for destination in &destinations {
+            prune_destination(&manifest_assets, &config, destination, options.delete, true).await?;
+        }
+        return Ok(());
+    }
+
+    // Record every name that had to be shortened so the mapping stays auditable
+    let renamed: Vec<serde_json::Value> = assets
+        .iter()
+        .filter_map(|asset| {
+            let local = asset.local_filename(config.max_filename_length);
+            (local != asset.filename).then(|| serde_json::json!({
+                "id": asset.id,
+                "original": asset.filename,
+                "local": local,
+            }))
+        })
+        .collect();
+    if !renamed.is_empty() {
+        status!("Truncated {} filenames longer than {} bytes", renamed.len(), config.max_filename_length);
+        let mapping = serde_json::to_string_pretty(&renamed)?;
+        for destination in &destinations {
+            fs::write(destination.join("truncated_filenames.json"), &mapping).await?;
+        }
+    }
+
+    // Files already present count as updates rather than additions in the summary
+    let existing: HashSet<String> = assets
+        .iter()
+        .filter(|asset| Downloader::local_file_path(asset, &config, destination).exists())
+        .map(|asset| asset.id.clone())
+        .collect();
+
+    // --resume picks partial files up instead
+    if !config.resume {
+        for destination in &destinations {
+            let removed = downloader::remove_stray_parts(destination).await?;
+            if removed > 0 {
+                status!("Removed {} partial files left in {} by an interrupted run", removed, destination.display());
+            }
+        }
+    }
+
+    let downloader = Downloader::new(config.clone());
+    // An aborted run (e.g. --abort-on-auth-failure) still writes its reports,
+    // cache and queue below; the error is returned once they're done
+    let aborted = downloader.download_files(&assets, destinations.clone()).await.err();
+
+    let skipped = downloader.skipped_files().await;
+    if !skipped.is_empty() {
+        status!("Skipped {} files without writing", skipped.len());
+    }
+
+    if let Some(cache) = &etag_cache {
+        cache.save().await?;
+    }
+
+    if !config.store_compressed.is_empty() {
+        let compressed = downloader.compressed_files().await;
+        if !compressed.is_empty() {
+            let original: u64 = compressed.iter().map(|file| file.original_size).sum();
+            let stored: u64 = compressed.iter().map(|file| file.compressed_size).sum();
+            status!(
+                "Stored {} files compressed: {} bytes -> {} bytes",
+                compressed.len(),
+                original,
+                stored
+            );
+            let report_path = destination.join("compressed_files.json");
+            fs::write(&report_path, serde_json::to_string_pretty(&compressed)?)
+                .await
+                .context(format!("Failed to write {}", report_path.display()))?;
+        }
+    }
+
+    for rate in downloader.host_rates().await {
+        if rate.throttled > 0 {
+            status!(
+                "Rate limited by {}: {} of {} requests got 429, adapted to {}ms extra delay and {} concurrent requests",
+                rate.host, rate.throttled, rate.requests, rate.delay_ms, rate.concurrency
+            );
+        }
+    }
+
+    if options.verify_after_sync || options.verify_sample.is_some() {
+        status!("Verifying downloaded files...");
+        // A sample run checks every size but leaves hashing to the sample
+        let failures = downloader.verify_written_files(options.verify_after_sync).await;
+        if failures == 0 {
+            status!("All downloaded files verified");
+        } else {
+            status!("{} files failed verification", failures);
+        }
+    }
+
+    if let Some(sample) = &options.verify_sample {
+        let coverage = downloader.verify_sampled_hashes(sample).await;
+        let byte_share = if coverage.total_bytes > 0 {
+            coverage.hashed_bytes as f64 * 100.0 / coverage.total_bytes as f64
+        } else {
+            100.0
+        };
+        status!(
+            "Hash sample (seed {}): {} of {} files, {:.1}% of bytes, {} mismatched",
+            sample.seed,
+            coverage.hashed_files,
+            coverage.total_files,
+            byte_share,
+            coverage.failures
+        );
+    }
+
+    let mut hook_failed = 0;
+    if !options.post_hooks.is_empty() {
+        let failed = downloader.failed_downloads().await;
+        let files: Vec<(DrupalFileAsset, PathBuf)> = downloader
+            .written_files()
+            .await
+            .into_iter()
+            .filter(|(asset, destination)| {
+                let destination = destination.display().to_string();
+                !failed
+                    .iter()
+                    .any(|f| f.id == asset.id && f.destination.as_deref() == Some(destination.as_str()))
+            })
+            .map(|(asset, destination)| {
+                let path = Downloader::local_file_path(&asset, &config, &destination);
+                (asset, path)
+            })
+            .collect();
+
+        let (runs, hook_failures) = hooks::run_post_hooks(
+            &options.post_hooks,
+            &files,
+            config.max_concurrent,
+            std::time::Duration::from_secs(options.post_hook_timeout),
+        )
+        .await;
+        status!("Post-hooks: {} runs, {} failed", runs, hook_failures.len());
+
+        if !hook_failures.is_empty() {
+            for failure in &hook_failures {
+                status!("  {} on {}: {}", failure.hook, failure.path, failure.error);
+            }
+            let report_path = destination.join("post_hook_failures.json");
+            fs::write(&report_path, serde_json::to_string_pretty(&hook_failures)?)
+                .await
+                .context(format!("Failed to write {}", report_path.display()))?;
+        }
+        hook_failed = hook_failures.len();
+    }
+
+    if let Some(work_queue) = queue.as_mut() {
+        let written = downloader.written_files().await;
+        let failures = downloader.failed_downloads().await;
+        for asset in &assets {
+            let writes = written.iter().filter(|(a, _)| a.id == asset.id).count();
+            let error = failures
+                .iter()
+                .find(|failed| failed.id == asset.id)
+                .map(|failed| failed.error.clone());
+            let done = (writes == destinations.len() && error.is_none())
+                || skipped.iter().any(|a| a.id == asset.id);
+            work_queue.complete(
+                &asset.id,
+                if done { None } else { Some(error.unwrap_or_else(|| "not written".to_string())) },
+            );
+        }
+        work_queue.save().await?;
+        let counts = work_queue.counts();
+        status!(
+            "Queue: {} done, {} failed, {} pending, {} in progress",
+            counts.done, counts.failed, counts.pending, counts.in_progress
+        );
+    }
+
+    if let Some(path) = &reports.dedupe_report {
+        let report = DedupeReport::from_hashes(&downloader.file_hashes().await);
+        report.save_to_file(path).await?;
+        status!(
+            "Dedupe report written to {}: {} duplicate groups, {} bytes wasted",
+            path.display(),
+            report.duplicate_groups.len(),
+            report.wasted_bytes
+        );
+    }
+
+    if let Some(dir) = &reports.report_dir {
+        fs::create_dir_all(dir)
+            .await
+            .context(format!("Failed to create report directory {}", dir.display()))?;
+        let log_manager = LogManager::new(dir.to_path_buf(), reports.max_reports, reports.retention_days)
+            .with_name_template(reports.name.clone());
+        let mut records = build_sync_records(&downloader, &config, &sync_id, &options.config_id).await;
+        let writer = log_manager
+            .create_log_file(&options.config_id, &sync_id)
+            .await?
+            .with_formats(&reports.formats);
+        writer.write_sync_records(&records).await?;
+        if writer.wants(ReportFormat::Html) {
+            // The page also lists failures so they stand out next to the successes
+            let failures = downloader.failed_downloads().await;
+            records.extend(failures.iter().map(|failed| SyncRecord {
+                sync_id: sync_id.clone(),
+                timestamp: failed.timestamp,
+                operation: "add".to_string(),
+                file_path: failed.filename.clone(),
+                file_size: 0,
+                status: "failed".to_string(),
+                error: Some(failed.error.clone()),
+                source: failed.path.clone(),
+                destination: failed.destination.clone().unwrap_or_default(),
+                md5: String::new(),
+                config_id: options.config_id.clone(),
+            }));
+            let summary = build_sync_summary(&downloader, &config, &sync_id, &assets, &existing, started).await;
+            writer.write_html(&records, &summary).await?;
+        }
+        log_manager.rotate_logs().await?;
+        status!("Wrote sync log with {} records to {}", records.len(), dir.display());
+    }
+
+    if let Some(path) = &reports.sqlite {
+        let records = build_sync_records(&downloader, &config, &sync_id, &options.config_id).await;
+        let failures: Vec<FailureRecord> = downloader
+            .failed_downloads()
+            .await
+            .iter()
+            .map(|failed| FailureRecord::from_failed_download(failed, &sync_id, &options.config_id))
+            .collect();
+        SqliteWriter::open(path)?.write_run(&records, &failures)?;
+        status!(
+            "Wrote {} sync records and {} failures to {}",
+            records.len(),
+            failures.len(),
+            path.display()
+        );
+    }
+
+    if options.preserve_empty_dirs {
+        create_manifest_directories(&manifest.directories, &destinations, config.path_separator, config.dir_mode)
+            .await?;
+    }
+
+    if destinations.len() > 1 {
+        status!("Per-destination results:");
+        for report in downloader.destination_reports().await {
+            status!(
+                "  {}: {} written, {} failed",
+                report.destination.display(),
+                report.written,
+                report.failed
+            );
+        }
+    }
+
+    // Written last so it includes verification failures
+    let failures_report = failures_base;
+    let failed = downloader.failed_downloads().await.len();
+    if failed > 0 {
+        downloader.write_failures(&failures_report, &sync_id, &options.config_id).await?;
+        status!(
+            "Wrote {} failures to {}.csv and {}.json",
+            failed,
+            failures_report.display(),
+            failures_report.display()
+        );
+    } else {
+        // Don't leave a previous run's failures looking current
+        for extension in ["csv", "json"] {
+            let _ = fs::remove_file(failures_report.with_extension(extension)).await;
+        }
+    }
+
+    // Pruning waits for a clean run so a failed download never costs a file
+    if failed == 0 && aborted.is_none() {
+        for destination in &destinations {
+            prune_destination(&manifest_assets, &config, destination, options.delete, false).await?;
+        }
+        promote_baseline(destination).await?;
+    }
 
-        /// Password for file downloads
-        #[arg(long)]
-        download_password: Option<String>,
+    let summary = build_sync_summary(&downloader, &config, &sync_id, &assets, &existing, started).await;
+    summary.save(&destination.join("sync_summary")).await?;
+    if output::json_lines() {
+        let mut event = serde_json::to_value(&summary)?;
+        event["event"] = "summary".into();
+        output::print_event(&event);
+    } else if output::summary_only() {
+        summary.print();
+    } else {
+        println!("{}", summary.one_line());
+    }
 
-        /// Delay between downloads in milliseconds
-        #[arg(long, default_value_t = 100)]
-        download_delay: u64,
+    if let Some(e) = aborted {
+        return Err(e);
+    }
 
-        /// Download timeout in seconds
-        #[arg(long, default_value_t = 60)]
-        download_timeout: u64,
+    if options.fail_on_hook_error && hook_failed > 0 {
+        return Err(anyhow::anyhow!("{} post-hook runs failed", hook_failed));
+    }
 
-        /// Maximum number of retries for failed downloads
-        #[arg(long, default_value_t = 3)]
-        max_retries: usize,
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "Sync finished with {} failures; see {}.csv",
+            failed,
+            failures_report.display()
+        ));
+    }
 
-        /// Force download even if file exists
-        #[arg(long)]
-        force: bool,
-    },
+    Ok(())
 }
 
-async fn get_config_dir() -> Result<PathBuf> {
-    Ok(dirs::config_dir()
-        .context("Failed to get config directory")?
-        .join("cli-file-sync"))
+/// Makes this run's saved manifest the next incremental sync's baseline.
+/// Only called after a clean run, so an interrupted or failed one never
+/// hides changed assets.
+async fn promote_baseline(destination: &Path) -> Result<()> {
+    let metadata_path = destination.join("assets.json");
+    if !metadata_path.exists() {
+        return Ok(());
+    }
+    let baseline = destination.join(BASELINE_FILE);
+    let tmp_path = baseline.with_extension("json.tmp");
+    fs::copy(&metadata_path, &tmp_path)
+        .await
+        .context(format!("Failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, &baseline)
+        .await
+        .context(format!("Failed to move {} into place", baseline.display()))?;
+    Ok(())
 }
 
-/// Compare two asset lists and return only the changed or new assets
-fn get_changed_assets(old_assets: &[DrupalFileAsset], new_assets: &[DrupalFileAsset]) -> Vec<DrupalFileAsset> {
-    let mut changed = Vec::new();
-    let old_map: HashMap<_, _> = old_assets
-        .iter()
-        .map(|asset| (asset.id.clone(), asset))
+/// Narrows a manifest to the assets a sync considers: resuming after
+/// `continue_from_id`, then diffing against `diff_base`, then tag, MIME type,
+/// extension and path filters
+async fn select_assets(
+    assets: Vec<DrupalFileAsset>,
+    continue_from_id: Option<&str>,
+    diff_base: Option<&Path>,
+    include_tags: &[String],
+    exclude_tags: &[String],
+    include_mime: &[glob::Pattern],
+    exclude_ext: &[String],
+    include_paths: &[String],
+    exclude_paths: &[String],
+) -> Result<Vec<DrupalFileAsset>> {
+    let assets = match continue_from_id {
+        Some(id) => {
+            let position = assets
+                .iter()
+                .position(|asset| asset.id == id)
+                .ok_or_else(|| anyhow::anyhow!("--continue-from-id: asset id '{}' not found in metadata", id))?;
+            status!("Continuing after asset {}: skipping {} assets", id, position + 1);
+            assets.into_iter().skip(position + 1).collect()
+        }
+        None => assets,
+    };
+
+    let assets = match diff_base {
+        Some(path) => {
+            let previous = load_manifest(path).await?;
+            let changed = get_changed_assets(&previous, &assets);
+            status!(
+                "Diffed against {}: {} of {} assets are new or changed",
+                path.display(),
+                changed.len(),
+                assets.len()
+            );
+            changed
+        }
+        None => assets,
+    };
+
+    let total = assets.len();
+    let assets: Vec<DrupalFileAsset> = assets
+        .into_iter()
+        .filter(|asset| asset.matches_tags(include_tags, exclude_tags))
         .collect();
+    if assets.len() < total {
+        status!("Filtered out {} assets by tag", total - assets.len());
+    }
 
-    for new_asset in new_assets {
-        match old_map.get(&new_asset.id) {
-            Some(old_asset) => {
-                if old_asset.changed != new_asset.changed {
-                    changed.push(new_asset.clone());
-                }
-            }
-            None => {
-                changed.push(new_asset.clone());
+    let total = assets.len();
+    let assets: Vec<DrupalFileAsset> = assets
+        .into_iter()
+        .filter(|asset| asset.matches_types(include_mime, exclude_ext))
+        .collect();
+    if assets.len() < total {
+        status!("Filtered out {} assets by MIME type or extension", total - assets.len());
+    }
+
+    let paths = PathFilter::new(include_paths, exclude_paths).map_err(anyhow::Error::msg)?;
+    let total = assets.len();
+    let assets: Vec<DrupalFileAsset> = assets.into_iter().filter(|asset| paths.matches(asset)).collect();
+    if assets.len() < total {
+        status!("Filtered out {} assets by path", total - assets.len());
+    }
+    Ok(assets)
+}
+
+/// Prints the assets a sync would consider, after the same filters
+async fn handle_list_command(
+    source: &str,
+    credentials: &Credentials,
+    continue_from_id: Option<&str>,
+    diff_base: Option<&Path>,
+    include_tags: &[String],
+    exclude_tags: &[String],
+    include_mime: &[glob::Pattern],
+    exclude_ext: &[String],
+    include_paths: &[String],
+    exclude_paths: &[String],
+    metadata_request: &MetadataRequest,
+    output: OutputFormat,
+) -> Result<()> {
+    let assets = if is_manifest_glob(source) {
+        read_manifest_glob(source, &FieldMap::default()).await?.files
+    } else {
+        let content = read_paged_manifest(source, credentials, metadata_request).await?;
+        serde_json::from_str::<DrupalFileAssetsResponse>(&content)
+            .context(format!("Failed to parse manifest {}", source))?
+            .into_vec()
+    };
+    let assets = select_assets(
+        assets,
+        continue_from_id,
+        diff_base,
+        include_tags,
+        exclude_tags,
+        include_mime,
+        exclude_ext,
+        include_paths,
+        exclude_paths,
+    )
+    .await?;
+
+    match output {
+        OutputFormat::Json => {
+            let listed: Vec<serde_json::Value> = assets
+                .iter()
+                .map(|asset| serde_json::json!({
+                    "id": asset.id,
+                    "filename": asset.filename,
+                    "path": asset.path,
+                    "size": asset.size,
+                    "mime": asset.mime,
+                }))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&listed)?);
+        }
+        OutputFormat::Text => {
+            println!("{:<10} {:<30} {:<40} {:>12} {}", "ID", "FILENAME", "PATH", "SIZE", "MIME");
+            for asset in &assets {
+                let size = asset.size.map(|size| size.to_string()).unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{:<10} {:<30} {:<40} {:>12} {}",
+                    asset.id, asset.filename, asset.path, size, asset.mime
+                );
             }
+            println!("{} assets", assets.len());
         }
     }
+    Ok(())
+}
 
-    changed
+/// Prints what a sync would add, update or leave unchanged in `destination`
+async fn print_change_plan(downloader: &Downloader, assets: &[DrupalFileAsset], destination: &Path) -> Result<()> {
+    println!("Dry run: checking {} assets against {}", assets.len(), destination.display());
+    let plan = downloader.plan_changes(assets, destination).await?;
+    print_plan(&plan);
+    Ok(())
 }
 
-async fn download_metadata(source: &str, destination: &Path, force: bool, username: Option<String>, password: Option<String>) -> Result<Vec<DrupalFileAsset>> {
-    // Create destination directory if it doesn't exist
-    println!("Ensuring destination directory exists: {}", destination.display());
-    if !destination.exists() {
-        tokio::fs::create_dir_all(destination).await.context(format!("Failed to create directory: {}", destination.display()))?;
+/// HEAD-checks every asset, writes `precheck.csv`/`.json` to `report_dir`
+/// and prints the assets with problems. Fails when any asset has one.
+async fn run_precheck(downloader: &Downloader, assets: &[DrupalFileAsset], report_dir: &Path) -> Result<()> {
+    println!("Pre-flight check: sending HEAD requests for {} assets", assets.len());
+    let results = downloader.precheck(assets).await?;
+
+    fs::create_dir_all(report_dir)
+        .await
+        .context(format!("Failed to create report directory {}", report_dir.display()))?;
+    let report = report_dir.join("precheck");
+    ReportWriter::new(report.clone(), "precheck")
+        .write_precheck_results(&results)
+        .await
+        .context(format!("Failed to write pre-flight report {}", report.display()))?;
+
+    let failed: Vec<_> = results.iter().filter(|result| !result.ok).collect();
+    for result in &failed {
+        println!("  {:<8} {} ({})", "problem", result.filename, result.problems);
     }
+    println!(
+        "Pre-flight check: {} ok, {} with problems; report written to {}",
+        results.len() - failed.len(),
+        failed.len(),
+        report.with_extension("csv").display()
+    );
+    if !failed.is_empty() {
+        return Err(anyhow::anyhow!("{} of {} assets failed the pre-flight check", failed.len(), results.len()));
+    }
+    Ok(())
+}
 
-    let metadata_path = destination.join("assets.json");
-    println!("Will save metadata to: {}", metadata_path.display());
-    
-    // First, always download or read the content
-    let content = if source.starts_with("http://") || source.starts_with("https://") {
-        println!("Downloading metadata from {}", source);
-        println!("This may take a while for large files...");
-        
-        let mut request = reqwest::Client::new().get(source);
-        
-        if let (Some(username), Some(password)) = (username, password) {
-            request = request.basic_auth(username, Some(password));
-        }
-        
-        let response = request.send().await.context("Failed to send HTTP request")?;
-        println!("Response status: {}", response.status());
-        
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Failed to download metadata: HTTP {} {}",
-                response.status().as_u16(),
-                response.status().as_str()
-            ));
-        }
-        
-        let content = response.text().await.context("Failed to read response body")?;
-        println!("Download complete! Content length: {} bytes", content.len());
-        if content.len() > 0 {
-            println!("Content preview: {}", &content[..std::cmp::min(content.len(), 200)]);
-        } else {
-            println!("Warning: Downloaded content is empty!");
-        }
-        
-        println!("Saving content to file: {}", metadata_path.display());
-        tokio::fs::write(&metadata_path, &content)
-            .await
-            .context(format!("Failed to write content to {}", metadata_path.display()))?;
-        
-        // Verify the file was written
-        if metadata_path.exists() {
-            println!("Successfully wrote metadata file");
-            let file_size = tokio::fs::metadata(&metadata_path)
-                .await
-                .map(|m| m.len())
-                .unwrap_or(0);
-            println!("File size: {} bytes", file_size);
-        } else {
-            println!("Warning: File was not created!");
+/// Prints one line per planned change followed by the totals
+fn print_plan(plan: &[PlannedChange]) {
+    let (mut added, mut updated, mut unchanged) = (0, 0, 0);
+    let mut bytes = 0u64;
+    for change in plan {
+        let label = match change.action {
+            ChangeAction::Add => {
+                added += 1;
+                "would-add"
+            }
+            ChangeAction::Update => {
+                updated += 1;
+                "would-update"
+            }
+            ChangeAction::Unchanged => {
+                unchanged += 1;
+                "unchanged"
+            }
+        };
+        if change.action != ChangeAction::Unchanged {
+            bytes += change.remote_size.unwrap_or(0);
         }
-        
-        content
-    } else {
-        println!("Reading local file {}", source);
-        tokio::fs::read_to_string(source).await?
-    };
+        println!("  {:<12} {} ({})", label, change.asset.filename, change.reason);
+    }
 
-    // Now try parsing the content
-    println!("Parsing metadata from {}...", metadata_path.display());
-    
-    // Try parsing as raw value first to understand the structure
-    match serde_json::from_str::<serde_json::Value>(&content) {
-        Ok(value) => {
-            println!("Successfully parsed as JSON. Root structure: {}", 
-                if value.is_object() { "object" }
-                else if value.is_array() { "array" }
-                else { "other" }
-            );
-            
-            if let Some(obj) = value.as_object() {
-                println!("Available fields at root: {:?}", obj.keys().collect::<Vec<_>>());
-                if let Some(files) = obj.get("files") {
-                    if let Some(files_arr) = files.as_array() {
-                        println!("Found files array with {} items", files_arr.len());
-                    } else {
-                        println!("'files' field is not an array");
-                    }
-                }
+    println!(
+        "Change plan: {} to add, {} to update, {} unchanged, about {} bytes to download",
+        added, updated, unchanged, bytes
+    );
+}
+
+/// Creates the manifest's declared directories in every destination.
+/// Entries that would escape the destination are skipped.
+async fn create_manifest_directories(
+    directories: &[String],
+    destinations: &[PathBuf],
+    path_separator: PathSeparator,
+    dir_mode: Option<u32>,
+) -> Result<()> {
+    let mut created = 0;
+    for directory in directories {
+        let normalized = path_separator.normalize(directory);
+        let relative = Path::new(normalized.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|component| !matches!(component, std::path::Component::Normal(_)))
+        {
+            status!("Skipping unsafe directory entry: {}", directory);
+            continue;
+        }
+        for destination in destinations {
+            let path = destination.join(relative);
+            if !path.exists() {
+                downloader::create_dir_all_with_mode(&path, dir_mode)
+                    .await
+                    .context(format!("Failed to create directory: {}", path.display()))?;
+                created += 1;
             }
         }
-        Err(e) => println!("Failed to parse as raw JSON: {}", e),
     }
-    
-    // Try parsing as a wrapper
-    match serde_json::from_str::<DrupalFileAssetsWrapper>(&content) {
-        Ok(wrapper) => {
-            println!("Successfully parsed as wrapper with {} files", wrapper.files.len());
-            Ok(wrapper.files)
+    if created > 0 {
+        status!("Created {} empty directories", created);
+    }
+    Ok(())
+}
+
+/// Runs `run_sync` every `interval` seconds until Ctrl-C.
+///
+/// A failed cycle is reported and the loop carries on; only an interrupt
+/// stops the watch.
+async fn watch_sync<F, Fut>(run_sync: F, interval: u64) -> Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let interval = std::time::Duration::from_secs(interval);
+    let mut cycle = 0u64;
+
+    loop {
+        cycle += 1;
+        status!("Starting watch cycle {}", cycle);
+        let started = std::time::Instant::now();
+
+        let result = tokio::select! {
+            result = run_sync() => result,
+            _ = tokio::signal::ctrl_c() => {
+                status!("Interrupted during watch cycle {}, stopping", cycle);
+                return Ok(());
+            }
+        };
+
+        let elapsed = started.elapsed().as_secs_f64();
+        match result {
+            Ok(()) => status!("Watch cycle {} completed in {:.1}s", cycle, elapsed),
+            Err(e) => output::print_notice(format!("Watch cycle {} failed after {:.1}s: {:#}", cycle, elapsed, e)),
         }
-        Err(wrapper_err) => {
-            // If that fails, try parsing as an array
-            match serde_json::from_str::<Vec<DrupalFileAsset>>(&content) {
-                Ok(assets) => {
-                    println!("Successfully parsed as array with {} files", assets.len());
-                    Ok(assets)
-                }
-                Err(array_err) => {
-                    println!("Failed to parse as wrapper: {}", wrapper_err);
-                    println!("Failed to parse as array: {}", array_err);
-                    Err(anyhow::anyhow!("Failed to parse metadata as JSON: {}", wrapper_err))
-                }
+
+        status!("Next sync in {}s (Ctrl-C to stop)", interval.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => {
+                status!("Stopping watch after {} cycles", cycle);
+                return Ok(());
             }
         }
     }
 }
 
-async fn handle_sync_command(
-    assets_metadata: &str,
-    destination: &Path,
-    base_url: &str,
-    max_concurrent: usize,
-    force: bool,
-    username: Option<String>,
-    password: Option<String>,
-) -> Result<()> {
-    // Get the current working directory
-    let current_dir = std::env::current_dir()?;
-    
-    // If destination is just a name (like "downloads"), make it relative to current directory
-    let destination = if destination.is_absolute() {
-        destination.to_path_buf()
-    } else {
-        current_dir.join(destination)
-    };
-
-    // Download or read metadata file
-    let assets = download_metadata(
-        assets_metadata,
-        &destination,
-        force,
-        username.clone(),
-        password.clone(),
-    )
-    .await?;
+/// Builds a success record for every file written by the downloader
+async fn build_sync_records(
+    downloader: &Downloader,
+    config: &DownloadConfig,
+    sync_id: &str,
+    config_id: &str,
+) -> Vec<SyncRecord> {
+    let sources = downloader.download_sources().await;
+    let md5s: HashMap<String, String> = downloader
+        .file_hashes()
+        .await
+        .into_iter()
+        .map(|hash| (hash.path, hash.md5))
+        .collect();
+    downloader
+        .written_files()
+        .await
+        .into_iter()
+        .map(|(asset, destination)| SyncRecord {
+            sync_id: sync_id.to_string(),
+            timestamp: chrono::Utc::now(),
+            operation: "add".to_string(),
+            file_path: asset.filename.clone(),
+            file_size: asset.size.unwrap_or(0),
+            status: "success".to_string(),
+            error: None,
+            // The mirror that served the file, when one was needed
+            source: sources
+                .get(&asset.id)
+                .cloned()
+                .unwrap_or_else(|| Downloader::get_download_url(&asset, config).unwrap_or_default()),
+            destination: Downloader::local_file_path(&asset, config, &destination)
+                .display()
+                .to_string(),
+            md5: md5s
+                .get(if asset.path.is_empty() { &asset.filename } else { &asset.path })
+                .cloned()
+                .unwrap_or_default(),
+            config_id: config_id.to_string(),
+        })
+        .collect()
+}
 
-    println!("Found {} assets to process", assets.len());
+/// Totals a finished run; `existing` holds the ids already on disk beforehand
+async fn build_sync_summary(
+    downloader: &Downloader,
+    config: &DownloadConfig,
+    sync_id: &str,
+    assets: &[DrupalFileAsset],
+    existing: &HashSet<String>,
+    started: std::time::Instant,
+) -> SyncSummary {
+    let failures = downloader.failed_downloads().await;
+    let failed: HashSet<&str> = failures.iter().map(|failed| failed.id.as_str()).collect();
+    let written: HashSet<String> = downloader
+        .written_files()
+        .await
+        .into_iter()
+        .map(|(asset, _)| asset.id)
+        .filter(|id| !failed.contains(id.as_str()))
+        .collect();
 
-    // Configure downloader
-    let config = DownloadConfig {
-        max_concurrent,
-        base_url: Some(base_url.to_string()),
-        username,
-        password,
+    let bytes: u64 = downloader.file_hashes().await.iter().map(|hash| hash.size).sum();
+    let duration_secs = started.elapsed().as_secs_f64();
+    let mut summary = SyncSummary {
+        sync_id: sync_id.to_string(),
+        total: assets.len(),
+        skipped: downloader.skipped_files().await.len(),
+        failed: failed.len(),
+        bytes,
+        duration_secs,
+        bytes_per_sec: if duration_secs > 0.0 { bytes as f64 / duration_secs } else { 0.0 },
         ..Default::default()
     };
+    for id in &written {
+        if existing.contains(id) {
+            summary.updated += 1;
+        } else {
+            summary.added += 1;
+        }
+    }
+    for asset in assets.iter().filter(|asset| failed.contains(asset.id.as_str())) {
+        let host = Downloader::get_download_url(asset, config)
+            .ok()
+            .and_then(|url| url::Url::parse(&url).ok())
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| "unknown host".to_string());
+        *summary.failures_by_host.entry(host).or_insert(0) += 1;
+    }
+    summary
+}
 
-    let downloader = Downloader::new(config);
-    downloader.download_files(&assets, destination).await?;
-
+async fn handle_config_action(action: ConfigAction) -> Result<()> {
+    match action {
+        ConfigAction::List => {
+            let configs = config::list_configs().await?;
+            if configs.is_empty() {
+                println!("No saved configurations in {}", CliConfig::config_dir()?.display());
+            }
+            for saved in configs {
+                let last_sync = saved
+                    .last_sync
+                    .map(|time| time.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_string());
+                println!(
+                    "{}\t{}\t{}",
+                    saved.id,
+                    saved.base_url.as_deref().unwrap_or("-"),
+                    last_sync
+                );
+            }
+        }
+        ConfigAction::Show { id } => {
+            let mut saved = CliConfig::load_raw(&id)
+                .await
+                .context(format!("Failed to load configuration '{}'", id))?;
+            let mask = |password: Option<String>| password.map(|_| "********".to_string());
+            saved.source_password = mask(saved.source_password);
+            saved.download_password = mask(saved.download_password);
+            println!("{}", serde_json::to_string_pretty(&saved)?);
+        }
+        ConfigAction::Delete { id } => {
+            config::delete_config(&id).await?;
+            println!("Deleted configuration '{}'", id);
+        }
+    }
     Ok(())
 }
 
 async fn handle_config_command(
+    config_id: &str,
     base_url: Option<String>,
     desti_path: Option<String>,
     source_username: Option<String>,
@@ -319,11 +2752,10 @@ async fn handle_config_command(
     download_timeout: u64,
     max_retries: usize,
     force: bool,
+    ttl: Option<u64>,
 ) -> Result<()> {
-    let config_id = "default"; // Use a default config ID
-    
     // Try to load existing config or create new one
-    let mut config = if let Ok(existing) = CliConfig::load(config_id).await {
+    let mut config = if let Ok(existing) = CliConfig::load_raw(config_id).await {
         existing
     } else {
         CliConfig::new(config_id.to_string(), ".".to_string()) // Default to current directory
@@ -345,11 +2777,16 @@ async fn handle_config_command(
     config.download_delay = download_delay;
     config.download_timeout = download_timeout;
     config.max_retries = max_retries;
+    if let Some(ttl) = ttl {
+        config.ttl = (ttl > 0).then_some(ttl);
+    }
 
     // Save the updated config
-    config.save().await?;
-
-    println!("Configuration updated successfully:");
+    if config.save_or_warn().await? {
+        println!("Configuration '{}' updated successfully:", config_id);
+    } else {
+        println!("Configuration '{}' applied for this run only:", config_id);
+    }
     println!("  Base URL: {:?}", config.base_url);
     println!("  Destination Path: {}", config.desti_path);
     println!("  Source Username: {:?}", config.source_username);
@@ -359,10 +2796,43 @@ async fn handle_config_command(
     println!("  Download Delay: {}ms", config.download_delay);
     println!("  Download Timeout: {}s", config.download_timeout);
     println!("  Max Retries: {}", config.max_retries);
+    match config.ttl {
+        Some(ttl) => println!("  TTL: {}s", ttl),
+        None => println!("  TTL: none"),
+    }
+
+    Ok(())
+}
+
+/// Re-reads the profile and, when its TTL hasn't expired, says why the
+/// sync is skipped
+async fn within_ttl(config_id: &str) -> Result<bool> {
+    let saved = CliConfig::load_raw(config_id)
+        .await
+        .context(format!("Failed to load config profile '{}'", config_id))?;
+    if saved.needs_sync() {
+        return Ok(false);
+    }
+    output::print_notice(format!(
+        "Sync not needed (last synced {}s ago, ttl {}s)",
+        saved.seconds_since_last_sync().unwrap_or(0),
+        saved.ttl.unwrap_or(0)
+    ));
+    Ok(true)
+}
 
+/// Stamps a profile's last successful sync, saving it as written so
+/// `${VAR}` references aren't replaced by their values
+async fn record_last_sync(config_id: &str) -> Result<()> {
+    let mut config = CliConfig::load_raw(config_id)
+        .await
+        .context(format!("Failed to load config profile '{}'", config_id))?;
+    config.update_last_sync();
+    config.save_or_warn().await?;
     Ok(())
 }
 
+/// Metadata source credentials from CLI_SYNC_SOURCE_USER/CLI_SYNC_SOURCE_PASS
 fn get_default_auth() -> (Option<String>, Option<String>) {
     let source_username = env::var("CLI_SYNC_SOURCE_USER").ok();
     let source_password = env::var("CLI_SYNC_SOURCE_PASS").ok();
@@ -373,6 +2843,11 @@ fn get_default_auth() -> (Option<String>, Option<String>) {
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    output::init_logging(cli.quiet, cli.verbose);
+    if let Some(dir) = cli.config_dir {
+        config::set_config_dir(dir);
+    }
+
     match cli.command {
         Commands::Sync {
             assets_metadata,
@@ -385,24 +2860,308 @@ async fn main() -> Result<()> {
             download_password,
             download_delay,
             download_timeout,
+            connect_timeout,
             max_retries,
             force,
+            diff_base,
+            verify_after_sync,
+            dedupe_report,
+            sqlite,
+            resolve_endpoint,
+            resolve_field,
+            max_filename_length,
+            continue_from_id,
+            zero_byte,
+            queue,
+            reset_queue,
+            size_tolerance,
+            verify_inline,
+            secrets_file,
+            normalize_line_endings,
+            metadata_method,
+            metadata_body,
+            metadata_content_type,
+            next_page_pointer,
+            max_pages,
+            watch,
+            interval,
+            preserve_empty_dirs,
+            ext_concurrency,
+            cas_dir,
+            dry_run_diff,
+            ramp_up,
+            post_hook,
+            post_hook_timeout,
+            fail_on_hook_error,
+            base_manifest,
+            patch_manifest,
+            report_dir,
+            max_reports,
+            report_retention_days,
+            verify_remote_unchanged,
+            min_tls_version,
+            summary_only,
+            etag_cache,
+            hash_concurrency,
+            path_separator,
+            max_manifest_age,
+            store_compressed,
+            abort_on_auth_failure,
+            report_name,
+            canonicalize_manifest,
+            verify_sample,
+            verify_sample_largest,
+            verify_sample_seed,
+            file_mode,
+            dir_mode,
+            failures_report,
+            max_bandwidth,
+            resume,
+            no_etag_cache,
+            config_id,
+            report_format,
+            log_format,
+            delete,
+            dry_run,
+            check_only,
+            field_map,
+            progress,
+            include_tag,
+            exclude_tag,
+            include_mime,
+            exclude_ext,
+            include_path,
+            exclude_path,
+            run_if_env,
         } => {
-            let assets_metadata = assets_metadata.ok_or_else(|| anyhow::anyhow!("No assets metadata provided"))?;
-            let destination = destination.unwrap_or_else(|| PathBuf::from("data"));
+            output::set_summary_only(summary_only);
+            output::set_log_format(log_format);
 
-            handle_sync_command(
-                &assets_metadata,
-                &destination,
-                &base_url,
+            // Evaluate the gate before any config or network work
+            if let Some(condition) = &run_if_env {
+                if !env_condition_met(condition) {
+                    return Ok(());
+                }
+            }
+
+            let assets_metadata = assets_metadata
+                .or(base_manifest)
+                .ok_or_else(|| anyhow::anyhow!("No assets metadata provided"))?;
+            let metadata_request = MetadataRequest::from_args(
+                metadata_method,
+                metadata_body,
+                metadata_content_type,
+                next_page_pointer,
+                max_pages,
+            )
+            .await?;
+
+            let secrets = match &secrets_file {
+                Some(path) => Secrets::load(path).await?,
+                None => Secrets::default(),
+            };
+            // A named profile must exist; the default one is used when saved
+            let saved = match config_id {
+                Some(id) => Some(
+                    CliConfig::load(&id)
+                        .await
+                        .context(format!("Failed to load config profile '{}'", id))?,
+                ),
+                None => match CliConfig::config_file("default") {
+                    Ok(path) if path.exists() => Some(CliConfig::load("default").await?),
+                    _ => None,
+                },
+            };
+            if let Some(saved) = &saved {
+                status!("Using config profile '{}'", saved.id);
+            }
+            // Checked before every run, so idle watch cycles stay cheap
+            let ttl_profile = saved
+                .as_ref()
+                .filter(|_| !force && !check_only)
+                .map(|saved| saved.id.clone());
+            // Previews don't count as a sync for the TTL
+            let profile = saved
+                .as_ref()
+                .filter(|_| !dry_run && !dry_run_diff && !check_only)
+                .map(|saved| saved.id.clone());
+            // Named in every report and failure record of the run
+            let config_id = saved.as_ref().map_or("default", |saved| saved.id.as_str());
+            let base_url = if base_url.is_empty() {
+                saved
+                    .as_ref()
+                    .and_then(|saved| saved.base_url.clone())
+                    .map(|url| vec![url])
+                    // A manifest of full URLs needs no base
+                    .unwrap_or_default()
+            } else {
+                base_url
+            };
+            let download_delay = download_delay
+                .or(saved.as_ref().map(|saved| saved.download_delay))
+                .unwrap_or(100);
+            let download_timeout = download_timeout
+                .or(saved.as_ref().map(|saved| saved.download_timeout))
+                .unwrap_or(60);
+            let max_retries = max_retries
+                .or(saved.as_ref().map(|saved| saved.max_retries))
+                .unwrap_or(3);
+
+            // The first of flags, secrets file, environment and saved config
+            // that sets any credential supplies all of them
+            let (env_source_username, env_source_password) = get_default_auth();
+            let source_credentials = Credentials {
+                username: source_username,
+                password: source_password,
+                token: None,
+            }
+            .or(secrets.source)
+            .or(Credentials {
+                username: env_source_username,
+                password: env_source_password,
+                token: None,
+            })
+            .or(Credentials {
+                username: saved.as_ref().and_then(|saved| saved.source_username.clone()),
+                password: saved.as_ref().and_then(|saved| saved.source_password.clone()),
+                token: None,
+            });
+            let download_credentials = Credentials {
+                username: download_username,
+                password: download_password,
+                token: None,
+            }
+            .or(secrets.download)
+            .or(Credentials {
+                username: saved.as_ref().and_then(|saved| saved.download_username.clone()),
+                password: saved.as_ref().and_then(|saved| saved.download_password.clone()),
+                token: None,
+            });
+            let verify_sample = verify_sample.map(|percent| VerifySample {
+                percent,
+                largest: verify_sample_largest,
+                seed: verify_sample_seed.unwrap_or_else(|| chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0) as u64),
+            });
+            let destinations = if destination.is_empty() {
+                vec![PathBuf::from("data")]
+            } else {
+                destination
+            };
+
+            let options = SyncOptions {
+                assets_metadata,
+                destinations,
+                source_credentials,
+                metadata_request,
+                field_map: FieldMap::new(field_map),
+                patch_manifests: patch_manifest,
+                max_manifest_age,
+                canonicalize_manifest,
+                continue_from_id,
+                diff_base,
+                include_tags: include_tag,
+                exclude_tags: exclude_tag,
+                include_mime,
+                exclude_ext,
+                include_paths: include_path,
+                exclude_paths: exclude_path,
+                use_queue: queue,
+                reset_queue,
+                etag_cache,
+                no_etag_cache,
+                verify_after_sync,
+                verify_sample,
+                post_hooks: post_hook,
+                post_hook_timeout,
+                fail_on_hook_error,
+                preserve_empty_dirs,
+                delete,
+                dry_run_diff,
+                check_only,
+                config_id: config_id.to_string(),
+            };
+            let reports = ReportOptions {
+                report_dir,
+                max_reports,
+                retention_days: report_retention_days,
+                name: report_name,
+                formats: report_format,
+                failures_report,
+                dedupe_report,
+                sqlite,
+            };
+            let config = DownloadConfig {
                 max_concurrent,
+                base_url: base_url.first().cloned(),
+                mirror_urls: base_url.iter().skip(1).cloned().collect(),
+                store_compressed,
+                auth_failure_limit: abort_on_auth_failure.then_some(downloader::AUTH_FAILURE_LIMIT),
+                file_mode,
+                dir_mode,
+                download_timeout,
+                connect_timeout,
+                download_delay,
+                max_bandwidth,
+                resume,
+                username: download_credentials.username,
+                password: download_credentials.password,
+                token: download_credentials.token,
+                resolve_endpoint,
+                resolve_field,
+                max_filename_length,
+                zero_byte,
+                size_tolerance,
+                verify_inline,
+                max_retries,
                 force,
-                download_username,
-                download_password,
-            )
-            .await
+                dry_run,
+                line_endings: normalize_line_endings,
+                ext_concurrency: ext_concurrency.into_iter().collect(),
+                cas_dir,
+                ramp_up,
+                verify_remote_unchanged,
+                min_tls_version,
+                hash_concurrency: hash_concurrency.unwrap_or_else(downloader::default_hash_concurrency),
+                path_separator,
+                ..Default::default()
+            };
+            let show_progress = progress && output::status_enabled() && std::io::stdout().is_terminal();
+
+            // Each run, and so each watch cycle, gets its own progress display
+            let run_sync = || {
+                let mut config = config.clone();
+                config.progress = show_progress.then(SyncProgress::new);
+                handle_sync_command(&options, &reports, config)
+            };
+
+            let run_sync = &run_sync;
+            let profile = &profile;
+            let ttl_profile = &ttl_profile;
+            let run_and_record = move || async move {
+                if let Some(id) = ttl_profile {
+                    if within_ttl(id).await? {
+                        return Ok(());
+                    }
+                }
+                run_sync().await?;
+                match profile {
+                    Some(id) => record_last_sync(id).await,
+                    None => Ok(()),
+                }
+            };
+
+            if !watch {
+                return run_and_record().await;
+            }
+
+            watch_sync(run_and_record, interval).await
         }
         Commands::Config {
+            action: Some(action),
+            ..
+        } => handle_config_action(action).await,
+        Commands::Config {
+            action: None,
             base_url,
             desti_path,
             source_username,
@@ -413,8 +3172,11 @@ async fn main() -> Result<()> {
             download_timeout,
             max_retries,
             force,
+            ttl,
+            config_id,
         } => {
             handle_config_command(
+                &config_id,
                 base_url,
                 desti_path,
                 source_username,
@@ -425,8 +3187,129 @@ async fn main() -> Result<()> {
                 download_timeout,
                 max_retries,
                 force,
+                ttl,
+            )
+            .await
+        }
+        Commands::Listing {
+            dir,
+            base_url,
+            output_listing_format,
+            output,
+            follow_symlinks,
+            max_depth,
+            ignore_file,
+            no_default_excludes,
+            checksums,
+            listing_concurrency,
+        } => {
+            let config = AssetListingConfig {
+                base_url,
+                output_path: dir.clone(),
+                output_file: output,
+                format: output_listing_format,
+                follow_symlinks,
+                max_depth,
+                ignore_file,
+                default_excludes: !no_default_excludes,
+                checksums,
+                concurrency: listing_concurrency,
+            };
+            assets::generate_asset_listing(&dir, &config).await?;
+            println!("Wrote asset listing to {}", config.output_file().display());
+            Ok(())
+        }
+        Commands::Docs {
+            output,
+            format,
+            combined,
+            role,
+        } => {
+            let generator = DocGenerator::new(output.clone());
+            if combined {
+                if format != DocFormat::Json {
+                    return Err(anyhow::anyhow!("--combined requires --format json"));
+                }
+                let path = generator.generate_combined_json(role).await?;
+                println!("Wrote combined documentation for role {} to {}", role, path.display());
+            } else if format == DocFormat::Markdown {
+                let path = generator.generate_markdown_file(role).await?;
+                println!("Wrote documentation for role {} to {}", role, path.display());
+            } else {
+                generator.generate_docs(format, role).await?;
+                println!("Wrote documentation tables for role {} to {}", role, output.display());
+            }
+            Ok(())
+        }
+        Commands::Validate {
+            assets_metadata,
+            source_username,
+            source_password,
+            max_filename_length,
+            next_page_pointer,
+            max_pages,
+            output,
+        } => {
+            let credentials = Credentials {
+                username: source_username,
+                password: source_password,
+                token: None,
+            };
+            let metadata_request = MetadataRequest::paged(next_page_pointer, max_pages);
+            handle_validate_command(&assets_metadata, &credentials, &metadata_request, max_filename_length, output)
+                .await
+        }
+        Commands::List {
+            assets_metadata,
+            source_username,
+            source_password,
+            diff_base,
+            next_page_pointer,
+            max_pages,
+            continue_from_id,
+            include_tag,
+            exclude_tag,
+            include_mime,
+            exclude_ext,
+            include_path,
+            exclude_path,
+            output,
+        } => {
+            // Keep stdout parseable when printing JSON
+            output::set_summary_only(output == OutputFormat::Json);
+            let credentials = Credentials {
+                username: source_username,
+                password: source_password,
+                token: None,
+            };
+            handle_list_command(
+                &assets_metadata,
+                &credentials,
+                continue_from_id.as_deref(),
+                diff_base.as_deref(),
+                &include_tag,
+                &exclude_tag,
+                &include_mime,
+                &exclude_ext,
+                &include_path,
+                &exclude_path,
+                &MetadataRequest::paged(next_page_pointer, max_pages),
+                output,
             )
             .await
         }
+        Commands::Schema { output } => {
+            let schema = serde_json::to_string_pretty(&schema::manifest_json_schema())?;
+            match output {
+                Some(path) => {
+                    fs::write(&path, schema)
+                        .await
+                        .context(format!("Failed to write schema to {}", path.display()))?;
+                    println!("Wrote manifest schema to {}", path.display());
+                }
+                None => println!("{}", schema),
+            }
+            Ok(())
+        }
     }
 }
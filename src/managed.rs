@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Name of the per-destination list of files written by syncs
+pub const MANAGED_FILE: &str = ".sync-files.json";
+
+/// Files a sync has placed in one destination, as paths relative to it.
+///
+/// Only files on this list are ever pruned, so anything else a user keeps
+/// in the destination is left alone.
+pub struct ManagedFiles {
+    destination: PathBuf,
+    files: BTreeSet<String>,
+}
+
+impl ManagedFiles {
+    /// Loads the list for `destination`, starting empty when there is none
+    pub async fn open(destination: &Path) -> Result<Self> {
+        let path = destination.join(MANAGED_FILE);
+        let files = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .await
+                .context(format!("Failed to read managed file list {}", path.display()))?;
+            serde_json::from_str(&content)
+                .context(format!("Failed to parse managed file list {}", path.display()))?
+        } else {
+            BTreeSet::new()
+        };
+        Ok(Self {
+            destination: destination.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Path of `file` relative to the destination, if it lies inside it
+    pub fn relative(&self, file: &Path) -> Option<String> {
+        file.strip_prefix(&self.destination)
+            .ok()
+            .map(|relative| relative.to_string_lossy().into_owned())
+    }
+
+    /// Tracked files still on disk that aren't in `expected`
+    pub fn orphans(&self, expected: &HashSet<String>) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|file| !expected.contains(*file) && self.destination.join(file).is_file())
+            .cloned()
+            .collect()
+    }
+
+    /// Deletes `orphans` and any directories they leave empty, returning
+    /// the number of files removed
+    pub async fn delete(&mut self, orphans: &[String]) -> Result<usize> {
+        for orphan in orphans {
+            let path = self.destination.join(orphan);
+            fs::remove_file(&path)
+                .await
+                .context(format!("Failed to delete {}", path.display()))?;
+            self.files.remove(orphan);
+
+            // Stops at the first directory that still has something in it
+            let mut parent = path.parent();
+            while let Some(dir) = parent.filter(|dir| *dir != self.destination && dir.starts_with(&self.destination)) {
+                if fs::remove_dir(dir).await.is_err() {
+                    break;
+                }
+                parent = dir.parent();
+            }
+        }
+        Ok(orphans.len())
+    }
+
+    /// Tracks the expected files present on disk, keeping earlier entries
+    /// that still exist so they can be pruned later
+    pub fn track(&mut self, expected: &HashSet<String>) {
+        let destination = &self.destination;
+        self.files.retain(|file| destination.join(file).is_file());
+        self.files.extend(
+            expected
+                .iter()
+                .filter(|file| destination.join(file).is_file())
+                .cloned(),
+        );
+    }
+
+    /// Persists the list with a write to a temp file followed by a rename
+    pub async fn save(&self) -> Result<()> {
+        let path = self.destination.join(MANAGED_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+        let content = serde_json::to_string_pretty(&self.files)?;
+        fs::write(&tmp_path, content)
+            .await
+            .context(format!("Failed to write managed file list {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .await
+            .context(format!("Failed to move managed file list into place at {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+use indicatif::MultiProgress;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static SUMMARY_ONLY: AtomicBool = AtomicBool::new(false);
+static JSON_LINES: AtomicBool = AtomicBool::new(false);
+static PROGRESS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// How a sync reports its progress on stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable status lines
+    Text,
+    /// One compact JSON object per file event and a final summary event
+    Jsonl,
+}
+
+/// Suppresses progress output so only the final summary is printed
+pub fn set_summary_only(enabled: bool) {
+    SUMMARY_ONLY.store(enabled, Ordering::Relaxed);
+}
+
+pub fn summary_only() -> bool {
+    SUMMARY_ONLY.load(Ordering::Relaxed)
+}
+
+/// Switches stdout to JSON Lines events, silencing status lines
+pub fn set_log_format(format: LogFormat) {
+    JSON_LINES.store(format == LogFormat::Jsonl, Ordering::Relaxed);
+}
+
+pub fn json_lines() -> bool {
+    JSON_LINES.load(Ordering::Relaxed)
+}
+
+/// Whether `status!` lines are printed
+pub fn status_enabled() -> bool {
+    !summary_only() && !json_lines()
+}
+
+/// Writes one event as a single line of compact JSON
+pub fn print_event(event: &serde_json::Value) {
+    println!("{}", event);
+}
+
+/// Prints a line people need to see even with `--summary-only`; in JSON
+/// Lines mode it goes to stderr so stdout stays machine-readable
+pub fn print_notice(line: String) {
+    if json_lines() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Sends status lines above the `--progress` bars instead of through them
+pub fn set_progress_target(bars: MultiProgress) {
+    *PROGRESS.lock().unwrap() = Some(bars);
+}
+
+pub fn print_status(line: String) {
+    match PROGRESS.lock().unwrap().as_ref() {
+        Some(bars) => {
+            let _ = bars.println(line);
+        }
+        None => println!("{}", line),
+    }
+}
+
+/// Routes this crate's `log` records to the terminal: info and debug lines
+/// as status lines, warnings and errors as notices
+struct StatusLogger;
+
+impl log::Log for StatusLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        // Dependencies such as reqwest log through the same facade
+        metadata.level() <= log::max_level() && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            log::Level::Error => print_notice(format!("Error: {}", record.args())),
+            log::Level::Warn => print_notice(format!("Warning: {}", record.args())),
+            _ if status_enabled() => print_status(record.args().to_string()),
+            _ => {}
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StatusLogger = StatusLogger;
+
+/// Installs the logger: `--quiet` keeps only warnings and errors, `--verbose`
+/// adds debug detail such as manifest previews
+pub fn init_logging(quiet: bool, verbose: bool) {
+    let level = if quiet {
+        log::LevelFilter::Warn
+    } else if verbose {
+        log::LevelFilter::Debug
+    } else {
+        log::LevelFilter::Info
+    };
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Logs a progress line at info level; hidden by `--quiet`, `--summary-only`
+/// and `--log-format jsonl`
+macro_rules! status {
+    ($($arg:tt)*) => {
+        log::info!($($arg)*)
+    };
+}
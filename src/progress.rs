@@ -0,0 +1,66 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+/// Progress display for a sync: one bar for the whole run plus a bar per
+/// download in flight. Clones share the same bars.
+#[derive(Clone)]
+pub struct SyncProgress {
+    bars: MultiProgress,
+    overall: ProgressBar,
+}
+
+impl SyncProgress {
+    /// Creates the display and routes status lines above the bars
+    pub fn new() -> Self {
+        let bars = MultiProgress::new();
+        let overall = bars.add(ProgressBar::new(0));
+        overall.set_style(
+            ProgressStyle::with_template("[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} files ({eta})")
+                .expect("valid progress template")
+                .progress_chars("=> "),
+        );
+        crate::output::set_progress_target(bars.clone());
+        Self { bars, overall }
+    }
+
+    pub fn set_total(&self, files: usize) {
+        self.overall.set_length(files as u64);
+    }
+
+    /// Adds a bar for one download; `size` is the expected body length
+    pub fn start_file(&self, name: &str, size: Option<u64>) -> ProgressBar {
+        let template = match size {
+            Some(_) => "  {spinner} {wide_msg} {bytes}/{total_bytes} {bytes_per_sec}",
+            None => "  {spinner} {wide_msg} {bytes} {bytes_per_sec}",
+        };
+        let bar = self.bars.add(match size {
+            Some(size) => ProgressBar::new(size),
+            None => ProgressBar::no_length(),
+        });
+        bar.set_style(ProgressStyle::with_template(template).expect("valid progress template"));
+        bar.set_message(name.to_string());
+        bar
+    }
+
+    /// Removes a finished download's bar
+    pub fn finish_file(&self, bar: &ProgressBar) {
+        bar.finish_and_clear();
+        self.bars.remove(bar);
+    }
+
+    /// Counts one asset as done, whatever its outcome
+    pub fn file_done(&self) {
+        self.overall.inc(1);
+    }
+
+    pub fn finish(&self) {
+        self.overall.finish();
+    }
+}
+
+impl std::fmt::Debug for SyncProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncProgress")
+            .field("files", &self.overall.position())
+            .finish()
+    }
+}
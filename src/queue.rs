@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::schema::DrupalFileAsset;
+
+/// Lifecycle state of a queued asset
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueState {
+    Pending,
+    InProgress,
+    Done,
+    Failed,
+}
+
+/// A single asset tracked by the work queue
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueueItem {
+    /// The asset to download
+    pub asset: DrupalFileAsset,
+    /// Current state of the item
+    pub state: QueueState,
+    /// Number of runs that have claimed this item
+    pub attempts: u32,
+    /// Last error recorded for the item
+    pub error: Option<String>,
+    /// When the item last changed state
+    pub updated: DateTime<Utc>,
+}
+
+/// Item totals per state
+#[derive(Debug, Default)]
+pub struct QueueCounts {
+    pub pending: usize,
+    pub in_progress: usize,
+    pub done: usize,
+    pub failed: usize,
+}
+
+/// Persistent work queue that lets a large sync span several runs.
+///
+/// Every state change is persisted with a write to a temp file followed by
+/// a rename, so a crash leaves either the old or the new queue on disk.
+/// Items a crashed run left `in_progress` are claimed again by the next run.
+pub struct WorkQueue {
+    path: PathBuf,
+    items: Vec<QueueItem>,
+}
+
+impl WorkQueue {
+    /// Loads the queue from `path`, starting empty when it doesn't exist
+    pub async fn open(path: &Path) -> Result<Self> {
+        let items = if path.exists() {
+            let content = fs::read_to_string(path)
+                .await
+                .context(format!("Failed to read queue {}", path.display()))?;
+            serde_json::from_str(&content)
+                .context(format!("Failed to parse queue {}", path.display()))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            items,
+        })
+    }
+
+    /// Removes the queue file so the next run starts fresh
+    pub async fn reset(path: &Path) -> Result<()> {
+        if path.exists() {
+            fs::remove_file(path)
+                .await
+                .context(format!("Failed to remove queue {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Adds assets that aren't queued yet and re-queues ones whose `changed`
+    /// timestamp moved. Returns the number of items made pending.
+    pub fn enqueue(&mut self, assets: &[DrupalFileAsset]) -> usize {
+        let mut queued = 0;
+        for asset in assets {
+            match self.items.iter_mut().find(|item| item.asset.id == asset.id) {
+                Some(item) if item.asset.changed != asset.changed => {
+                    item.asset = asset.clone();
+                    item.state = QueueState::Pending;
+                    item.error = None;
+                    item.updated = Utc::now();
+                    queued += 1;
+                }
+                Some(_) => {}
+                None => {
+                    self.items.push(QueueItem {
+                        asset: asset.clone(),
+                        state: QueueState::Pending,
+                        attempts: 0,
+                        error: None,
+                        updated: Utc::now(),
+                    });
+                    queued += 1;
+                }
+            }
+        }
+        queued
+    }
+
+    /// Moves every unfinished item to `in_progress` and returns the assets
+    /// this run should download. Failed items are retried.
+    pub fn claim(&mut self) -> Vec<DrupalFileAsset> {
+        let mut claimed = Vec::new();
+        for item in &mut self.items {
+            if item.state != QueueState::Done {
+                item.state = QueueState::InProgress;
+                item.attempts += 1;
+                item.updated = Utc::now();
+                claimed.push(item.asset.clone());
+            }
+        }
+        claimed
+    }
+
+    /// Records the outcome of a claimed item
+    pub fn complete(&mut self, id: &str, error: Option<String>) {
+        if let Some(item) = self.items.iter_mut().find(|item| item.asset.id == id) {
+            item.state = if error.is_some() {
+                QueueState::Failed
+            } else {
+                QueueState::Done
+            };
+            item.error = error;
+            item.updated = Utc::now();
+        }
+    }
+
+    /// Counts items per state
+    pub fn counts(&self) -> QueueCounts {
+        let mut counts = QueueCounts::default();
+        for item in &self.items {
+            match item.state {
+                QueueState::Pending => counts.pending += 1,
+                QueueState::InProgress => counts.in_progress += 1,
+                QueueState::Done => counts.done += 1,
+                QueueState::Failed => counts.failed += 1,
+            }
+        }
+        counts
+    }
+
+    /// Persists the queue atomically
+    pub async fn save(&self) -> Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        let content = serde_json::to_string_pretty(&self.items)?;
+        fs::write(&tmp_path, content)
+            .await
+            .context(format!("Failed to write queue {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .await
+            .context(format!("Failed to move queue into place at {}", self.path.display()))?;
+        Ok(())
+    }
+}
@@ -1,9 +1,12 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
 use tokio::fs;
-use csv::Writer;
+use csv::{Writer, WriterBuilder};
+
+use crate::downloader::{FailedDownload, FileHash, PrecheckResult};
 
 /// Represents a sync operation record for CSV export
 #[derive(Debug, Serialize, Deserialize)]
@@ -35,6 +38,8 @@ pub struct SyncRecord {
 /// Represents a failure record for CSV export
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FailureRecord {
+    /// Unique identifier of the sync run the failure belongs to
+    pub sync_id: String,
     /// Timestamp of the failure
     pub timestamp: DateTime<Utc>,
     /// File that failed
@@ -49,45 +54,344 @@ pub struct FailureRecord {
     pub config_id: String,
 }
 
-/// Report writer that handles both CSV and JSON formats
+impl FailureRecord {
+    /// Builds a failure record from a downloader failure
+    pub fn from_failed_download(failed: &FailedDownload, sync_id: &str, config_id: &str) -> Self {
+        let error_type = if failed.error.starts_with("Verification failed") {
+            "verification"
+        } else {
+            "download"
+        };
+        Self {
+            sync_id: sync_id.to_string(),
+            timestamp: failed.timestamp,
+            file: failed.filename.clone(),
+            error_type: error_type.to_string(),
+            error_message: failed.error.clone(),
+            details: failed.destination.clone().unwrap_or_default(),
+            config_id: config_id.to_string(),
+        }
+    }
+}
+
+/// Final totals of a sync run
+#[derive(Debug, Default, Serialize)]
+pub struct SyncSummary {
+    pub sync_id: String,
+    /// Assets the run considered
+    pub total: usize,
+    pub added: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Bytes fetched from the download servers
+    pub bytes: u64,
+    pub duration_secs: f64,
+    /// Average download rate over the whole run
+    pub bytes_per_sec: f64,
+    /// Failed assets per download host
+    pub failures_by_host: BTreeMap<String, usize>,
+}
+
+impl SyncSummary {
+    /// Single-line totals printed at the end of every sync
+    pub fn one_line(&self) -> String {
+        format!(
+            "Synced {} files: {} added, {} updated, {} skipped, {} failed; {} bytes in {:.1}s ({:.0} B/s)",
+            self.total,
+            self.added,
+            self.updated,
+            self.skipped,
+            self.failed,
+            self.bytes,
+            self.duration_secs,
+            self.bytes_per_sec
+        )
+    }
+
+    /// Multi-line human-readable summary
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Sync summary for run {}:\n", self.sync_id);
+        text.push_str(&format!("  {} files\n", self.total));
+        text.push_str(&format!(
+            "  {} added, {} updated, {} skipped, {} failed\n",
+            self.added, self.updated, self.skipped, self.failed
+        ));
+        text.push_str(&format!(
+            "  {} bytes downloaded in {:.1}s ({:.0} B/s)\n",
+            self.bytes, self.duration_secs, self.bytes_per_sec
+        ));
+        for (host, count) in &self.failures_by_host {
+            text.push_str(&format!("  {} failed from {}\n", count, host));
+        }
+        text
+    }
+
+    pub fn print(&self) {
+        print!("{}", self.to_text());
+    }
+
+    /// Writes the summary to `<base_path>.json` and `<base_path>.txt`
+    pub async fn save(&self, base_path: &Path) -> Result<()> {
+        let json_path = base_path.with_extension("json");
+        fs::write(&json_path, serde_json::to_string_pretty(self)?)
+            .await
+            .context(format!("Failed to write sync summary to {}", json_path.display()))?;
+        let text_path = base_path.with_extension("txt");
+        fs::write(&text_path, self.to_text())
+            .await
+            .context(format!("Failed to write sync summary to {}", text_path.display()))?;
+        Ok(())
+    }
+}
+
+/// Files sharing identical content
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DuplicateGroup {
+    /// SHA-256 of the shared content
+    pub sha256: String,
+    /// Size of one copy in bytes
+    pub size: u64,
+    /// Paths of every file with this content
+    pub paths: Vec<String>,
+    /// Bytes spent on the redundant copies
+    pub wasted_bytes: u64,
+}
+
+/// Summary of duplicate content across a sync
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DedupeReport {
+    /// Number of files hashed
+    pub total_files: usize,
+    /// Groups of files with identical content, largest waste first
+    pub duplicate_groups: Vec<DuplicateGroup>,
+    /// Total bytes spent on redundant copies
+    pub wasted_bytes: u64,
+}
+
+impl DedupeReport {
+    /// Groups hashed files by content
+    pub fn from_hashes(hashes: &[FileHash]) -> Self {
+        let mut by_hash: HashMap<&str, Vec<&FileHash>> = HashMap::new();
+        for hash in hashes {
+            by_hash.entry(hash.sha256.as_str()).or_default().push(hash);
+        }
+
+        let mut duplicate_groups: Vec<DuplicateGroup> = by_hash
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .map(|(sha256, files)| {
+                let size = files[0].size;
+                let mut paths: Vec<String> = files.iter().map(|f| f.path.clone()).collect();
+                paths.sort();
+                DuplicateGroup {
+                    sha256: sha256.to_string(),
+                    size,
+                    wasted_bytes: size * (files.len() as u64 - 1),
+                    paths,
+                }
+            })
+            .collect();
+        duplicate_groups.sort_by(|a, b| b.wasted_bytes.cmp(&a.wasted_bytes).then(a.sha256.cmp(&b.sha256)));
+
+        Self {
+            total_files: hashes.len(),
+            wasted_bytes: duplicate_groups.iter().map(|g| g.wasted_bytes).sum(),
+            duplicate_groups,
+        }
+    }
+
+    /// Saves the report as pretty-printed JSON
+    pub async fn save_to_file(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path, content)
+            .await
+            .context(format!("Failed to write dedupe report to {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Formats a sync log can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+    Html,
+}
+
+/// Report writer that handles CSV, JSON and HTML formats
 pub struct ReportWriter {
     csv_path: PathBuf,
     json_path: PathBuf,
+    html_path: PathBuf,
+    formats: Vec<ReportFormat>,
 }
 
 impl ReportWriter {
-    /// Creates a new report writer
+    /// Creates a new report writer producing CSV and JSON
     pub fn new(base_path: PathBuf, report_type: &str) -> Self {
         let csv_path = base_path.with_extension("csv");
         let json_path = base_path.with_extension("json");
-        Self { csv_path, json_path }
+        let html_path = base_path.with_extension("html");
+        Self {
+            csv_path,
+            json_path,
+            html_path,
+            formats: vec![ReportFormat::Csv, ReportFormat::Json],
+        }
+    }
+
+    /// Limits `write_sync_records` to the given formats
+    pub fn with_formats(mut self, formats: &[ReportFormat]) -> Self {
+        self.formats = formats.to_vec();
+        self
+    }
+
+    pub fn wants(&self, format: ReportFormat) -> bool {
+        self.formats.contains(&format)
+    }
+
+    /// Opens the CSV for appending, writing the header only when the file
+    /// is new or empty so records written one at a time accumulate
+    fn csv_appender(&self) -> Result<Writer<std::fs::File>> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.csv_path)
+            .context(format!("Failed to open {}", self.csv_path.display()))?;
+        let is_new = file.metadata()?.len() == 0;
+        Ok(WriterBuilder::new().has_headers(is_new).from_writer(file))
+    }
+
+    /// Adds records to the JSON array, keeping the ones already there so
+    /// the JSON grows alongside the appended CSV
+    async fn append_json<T: Serialize>(&self, records: &[T]) -> Result<()> {
+        let mut all = match fs::read_to_string(&self.json_path).await {
+            Ok(content) if !content.trim().is_empty() => {
+                match serde_json::from_str(&content)
+                    .context(format!("Failed to parse {}", self.json_path.display()))?
+                {
+                    serde_json::Value::Array(existing) => existing,
+                    // Reports written before records accumulated held a single object
+                    single => vec![single],
+                }
+            }
+            _ => Vec::new(),
+        };
+        for record in records {
+            all.push(serde_json::to_value(record)?);
+        }
+        fs::write(&self.json_path, serde_json::to_string_pretty(&all)?)
+            .await
+            .context(format!("Failed to write {}", self.json_path.display()))?;
+        Ok(())
     }
 
     /// Writes a sync record to both CSV and JSON
     pub async fn write_sync_record(&self, record: &SyncRecord) -> Result<()> {
         // Write to CSV
-        let mut wtr = Writer::from_path(&self.csv_path)?;
+        let mut wtr = self.csv_appender()?;
         wtr.serialize(record)?;
         wtr.flush()?;
 
         // Also keep JSON for compatibility
-        let json = serde_json::to_string_pretty(record)?;
-        fs::write(&self.json_path, json).await?;
+        self.append_json(std::slice::from_ref(record)).await?;
 
         Ok(())
     }
 
-    /// Writes multiple sync records
+    /// Writes multiple sync records in the writer's CSV and JSON formats
     pub async fn write_sync_records(&self, records: &[SyncRecord]) -> Result<()> {
+        if self.wants(ReportFormat::Csv) {
+            let mut wtr = self.csv_appender()?;
+            for record in records {
+                wtr.serialize(record)?;
+            }
+            wtr.flush()?;
+        }
+
+        if self.wants(ReportFormat::Json) {
+            self.append_json(records).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a self-contained HTML page with the summary above a table of
+    /// records that sorts by the clicked column
+    pub async fn write_html(&self, records: &[SyncRecord], summary: &SyncSummary) -> Result<()> {
+        let mut html = String::from("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str(&format!("<title>Sync report {}</title>\n", html_escape(&summary.sync_id)));
+        html.push_str(HTML_REPORT_STYLE);
+        html.push_str("</head>\n<body>\n");
+        html.push_str(&format!("<h1>Sync report {}</h1>\n<ul class=\"summary\">\n", html_escape(&summary.sync_id)));
+        for (label, value) in [
+            ("Files", summary.total.to_string()),
+            ("Added", summary.added.to_string()),
+            ("Updated", summary.updated.to_string()),
+            ("Skipped", summary.skipped.to_string()),
+            ("Failed", summary.failed.to_string()),
+            ("Bytes", summary.bytes.to_string()),
+            ("Duration", format!("{:.1}s", summary.duration_secs)),
+            ("Throughput", format!("{:.0} B/s", summary.bytes_per_sec)),
+        ] {
+            html.push_str(&format!("<li><span>{}</span> {}</li>\n", label, value));
+        }
+        html.push_str("</ul>\n<table>\n<thead><tr>");
+        for column in ["Time", "Status", "File", "Size", "Source", "Destination", "Error"] {
+            html.push_str(&format!("<th>{}</th>", column));
+        }
+        html.push_str("</tr></thead>\n<tbody>\n");
+        for record in records {
+            html.push_str(&format!(
+                "<tr class=\"status-{}\"><td>{}</td><td>{}</td><td>{}</td><td data-sort=\"{}\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                html_escape(&record.status),
+                record.timestamp.to_rfc3339(),
+                html_escape(&record.status),
+                html_escape(&record.file_path),
+                record.file_size,
+                record.file_size,
+                html_escape(&record.source),
+                html_escape(&record.destination),
+                html_escape(record.error.as_deref().unwrap_or("")),
+            ));
+        }
+        html.push_str("</tbody>\n</table>\n");
+        html.push_str(HTML_REPORT_SCRIPT);
+        html.push_str("</body>\n</html>\n");
+
+        fs::write(&self.html_path, html)
+            .await
+            .context(format!("Failed to write HTML report {}", self.html_path.display()))?;
+        Ok(())
+    }
+
+    /// Writes multiple failure records
+    pub async fn write_failure_records(&self, records: &[FailureRecord]) -> Result<()> {
         // Write to CSV
-        let mut wtr = Writer::from_path(&self.csv_path)?;
+        let mut wtr = self.csv_appender()?;
         for record in records {
             wtr.serialize(record)?;
         }
         wtr.flush()?;
 
         // Also keep JSON for compatibility
-        let json = serde_json::to_string_pretty(records)?;
+        self.append_json(records).await?;
+
+        Ok(())
+    }
+
+    /// Writes pre-flight results to both CSV and JSON, replacing any
+    /// earlier report
+    pub async fn write_precheck_results(&self, results: &[PrecheckResult]) -> Result<()> {
+        let mut wtr = Writer::from_path(&self.csv_path)
+            .context(format!("Failed to create {}", self.csv_path.display()))?;
+        for result in results {
+            wtr.serialize(result)?;
+        }
+        wtr.flush()?;
+
+        let json = serde_json::to_string_pretty(results)?;
         fs::write(&self.json_path, json).await?;
 
         Ok(())
@@ -96,63 +400,331 @@ impl ReportWriter {
     /// Writes a failure record
     pub async fn write_failure_record(&self, record: &FailureRecord) -> Result<()> {
         // Write to CSV
-        let mut wtr = Writer::from_path(&self.csv_path)?;
+        let mut wtr = self.csv_appender()?;
         wtr.serialize(record)?;
         wtr.flush()?;
 
         // Also keep JSON for compatibility
-        let json = serde_json::to_string_pretty(record)?;
-        fs::write(&self.json_path, json).await?;
+        self.append_json(std::slice::from_ref(record)).await?;
 
         Ok(())
     }
 }
 
+const HTML_REPORT_STYLE: &str = r#"<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+.summary { display: flex; flex-wrap: wrap; gap: 1em; list-style: none; padding: 0; }
+.summary li { background: #f3f3f3; border-radius: 4px; padding: 0.5em 1em; }
+.summary span { display: block; font-size: 0.8em; color: #666; }
+table { border-collapse: collapse; width: 100%; font-size: 0.9em; }
+th, td { border: 1px solid #ddd; padding: 4px 8px; text-align: left; }
+th { background: #eee; cursor: pointer; user-select: none; }
+tr.status-success td:nth-child(2) { color: #1a7f37; font-weight: bold; }
+tr.status-failed td:nth-child(2) { color: #cf222e; font-weight: bold; }
+tr.status-failed { background: #fff0f0; }
+</style>
+"#;
+
+const HTML_REPORT_SCRIPT: &str = r#"<script>
+document.querySelectorAll('th').forEach((th, column) => th.addEventListener('click', () => {
+  const body = th.closest('table').tBodies[0];
+  const ascending = th.dataset.order !== 'asc';
+  th.dataset.order = ascending ? 'asc' : 'desc';
+  const key = row => row.cells[column].dataset.sort ?? row.cells[column].textContent;
+  const rows = Array.from(body.rows).sort((a, b) => {
+    const x = key(a), y = key(b);
+    const numeric = x !== '' && y !== '' && !isNaN(x) && !isNaN(y);
+    const order = numeric ? Number(x) - Number(y) : x.localeCompare(y);
+    return ascending ? order : -order;
+  });
+  rows.forEach(row => body.appendChild(row));
+}));
+</script>
+"#;
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes sync and failure records into a SQLite database
+pub struct SqliteWriter {
+    conn: rusqlite::Connection,
+}
+
+impl SqliteWriter {
+    /// Opens the database, creating the schema on first use
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)
+            .context(format!("Failed to open SQLite database {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sync_records (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sync_id TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                operation TEXT NOT NULL,
+                file_path TEXT NOT NULL,
+                file_size INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                error TEXT,
+                source TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                md5 TEXT NOT NULL,
+                config_id TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS failure_records (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                sync_id TEXT NOT NULL,
+                timestamp TEXT NOT NULL,
+                file TEXT NOT NULL,
+                error_type TEXT NOT NULL,
+                error_message TEXT NOT NULL,
+                details TEXT NOT NULL,
+                config_id TEXT NOT NULL
+            );",
+        )
+        .context("Failed to create SQLite schema")?;
+        Ok(Self { conn })
+    }
+
+    /// Appends one run's records in a single transaction
+    pub fn write_run(
+        &mut self,
+        records: &[SyncRecord],
+        failures: &[FailureRecord],
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert_record = tx.prepare(
+                "INSERT INTO sync_records (sync_id, timestamp, operation, file_path, file_size, status, error, source, destination, md5, config_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for record in records {
+                insert_record.execute(rusqlite::params![
+                    record.sync_id,
+                    record.timestamp.to_rfc3339(),
+                    record.operation,
+                    record.file_path,
+                    record.file_size as i64,
+                    record.status,
+                    record.error,
+                    record.source,
+                    record.destination,
+                    record.md5,
+                    record.config_id,
+                ])?;
+            }
+
+            let mut insert_failure = tx.prepare(
+                "INSERT INTO failure_records (sync_id, timestamp, file, error_type, error_message, details, config_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for failure in failures {
+                insert_failure.execute(rusqlite::params![
+                    failure.sync_id,
+                    failure.timestamp.to_rfc3339(),
+                    failure.file,
+                    failure.error_type,
+                    failure.error_message,
+                    failure.details,
+                    failure.config_id,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// Placeholders a report filename template may use
+const REPORT_NAME_FIELDS: &[&str] = &["profile", "date", "time", "sync_id"];
+
+/// Report filename such as `{profile}_{date}_{sync_id}`, checked against
+/// the known placeholders when parsed
+#[derive(Debug, Clone)]
+pub struct ReportNameTemplate(String);
+
+impl ReportNameTemplate {
+    pub const DEFAULT: &'static str = "sync_log_{date}_{time}";
+
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed placeholder in '{}'", template))?;
+            let field = &rest[start + 1..start + end];
+            if !REPORT_NAME_FIELDS.contains(&field) {
+                return Err(format!(
+                    "unknown placeholder {{{}}} in '{}' (available: {})",
+                    field,
+                    template,
+                    REPORT_NAME_FIELDS.join(", ")
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        // The name gets .csv/.json appended and must stay inside the log directory
+        if template.contains(['/', '\\', '.']) || template.trim().is_empty() {
+            return Err(format!("'{}' must be a plain file name without '/', '\\' or '.'", template));
+        }
+        Ok(Self(template.to_string()))
+    }
+
+    /// Fills in the placeholders for one run
+    pub fn render(&self, profile: &str, sync_id: &str, now: DateTime<Utc>) -> String {
+        self.0
+            .replace("{profile}", profile)
+            .replace("{date}", &now.format("%Y%m%d").to_string())
+            .replace("{time}", &now.format("%H%M%S").to_string())
+            .replace("{sync_id}", sync_id)
+    }
+
+    /// Whether `stem` could have been produced by `render`, so rotation only
+    /// touches this template's logs
+    pub fn matches(&self, stem: &str) -> bool {
+        let mut parts = Vec::new();
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').unwrap_or(rest.len() - start);
+            parts.push(NamePart::Literal(&rest[..start]));
+            parts.push(match &rest[start + 1..end] {
+                "date" => NamePart::Digits(8),
+                "time" => NamePart::Digits(6),
+                _ => NamePart::Any,
+            });
+            rest = rest.get(end + 1..).unwrap_or("");
+        }
+        parts.push(NamePart::Literal(rest));
+        match_name_parts(&parts, stem)
+    }
+}
+
+enum NamePart<'a> {
+    Literal(&'a str),
+    Digits(usize),
+    /// {profile} and {sync_id}: any non-empty text
+    Any,
+}
+
+fn match_name_parts(parts: &[NamePart], stem: &str) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return stem.is_empty();
+    };
+    match part {
+        NamePart::Literal(literal) => stem
+            .strip_prefix(literal)
+            .is_some_and(|stem| match_name_parts(rest, stem)),
+        NamePart::Digits(n) => {
+            stem.len() >= *n
+                && stem.as_bytes()[..*n].iter().all(u8::is_ascii_digit)
+                && match_name_parts(rest, &stem[*n..])
+        }
+        NamePart::Any => (1..=stem.len())
+            .filter(|&i| stem.is_char_boundary(i))
+            .any(|i| match_name_parts(rest, &stem[i..])),
+    }
+}
+
+impl Default for ReportNameTemplate {
+    fn default() -> Self {
+        Self(Self::DEFAULT.to_string())
+    }
+}
+
 /// Manages the log directory and rotation
 pub struct LogManager {
     log_dir: PathBuf,
     max_logs: u32,
+    /// Logs older than this many days are removed regardless of count
+    retention_days: Option<u32>,
+    name_template: ReportNameTemplate,
 }
 
 impl LogManager {
     /// Creates a new log manager
-    pub fn new(log_dir: PathBuf, max_logs: u32) -> Self {
-        Self { log_dir, max_logs }
+    pub fn new(log_dir: PathBuf, max_logs: u32, retention_days: Option<u32>) -> Self {
+        Self {
+            log_dir,
+            max_logs,
+            retention_days,
+            name_template: ReportNameTemplate::default(),
+        }
     }
 
-    /// Creates a new log file with current timestamp
-    pub async fn create_log_file(&self) -> Result<ReportWriter> {
-        let timestamp = Utc::now().format("%Y%m%d_%H%M%S");
-        let base_path = self.log_dir.join(format!("sync_log_{}", timestamp));
+    /// Names log files from `template` instead of `sync_log_<timestamp>`
+    pub fn with_name_template(mut self, template: ReportNameTemplate) -> Self {
+        self.name_template = template;
+        self
+    }
+
+    /// Creates a new log file named from the template for this run
+    pub async fn create_log_file(&self, profile: &str, sync_id: &str) -> Result<ReportWriter> {
+        let name = self.name_template.render(profile, sync_id, Utc::now());
+        let base_path = self.log_dir.join(name);
         Ok(ReportWriter::new(base_path, "sync_log"))
     }
 
-    /// Rotates logs based on max_logs configuration
+    /// Rotates logs, removing any older than the retention period and then
+    /// the oldest beyond max_logs. A log is every `.csv`/`.json`/`.html` file sharing
+    /// one stem that the name template could have produced, dated by its newest
+    /// file; anything else is left alone.
     pub async fn rotate_logs(&self) -> Result<()> {
-        let mut entries: Vec<_> = fs::read_dir(&self.log_dir)
-            .await?
-            .filter_map(|e| e.ok())
-            .collect();
-
-        // Sort by modified time
-        entries.sort_by_key(|e| {
-            e.metadata()
-                .unwrap()
+        let mut logs: HashMap<String, (std::time::SystemTime, Vec<PathBuf>)> = HashMap::new();
+        let mut read_dir = fs::read_dir(&self.log_dir).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            let path = entry.path();
+            let is_log = matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("csv") | Some("json") | Some("html")
+            );
+            let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(stem)
+                    if is_log
+                        && self.name_template.matches(stem)
+                        && entry.file_type().await?.is_file() =>
+                {
+                    stem.to_string()
+                }
+                _ => continue,
+            };
+            let modified = entry
+                .metadata()
+                .await?
                 .modified()
-                .unwrap_or(std::time::SystemTime::UNIX_EPOCH)
-        });
+                .unwrap_or(std::time::SystemTime::UNIX_EPOCH);
+            let log = logs
+                .entry(stem)
+                .or_insert((std::time::SystemTime::UNIX_EPOCH, Vec::new()));
+            log.0 = log.0.max(modified);
+            log.1.push(path);
+        }
 
-        // Group CSV and JSON files together
-        let mut files_to_remove = Vec::new();
-        let num_pairs = entries.len() / 2;
-        if num_pairs > self.max_logs as usize {
-            let to_remove = num_pairs - self.max_logs as usize;
-            files_to_remove.extend(entries.iter().take(to_remove * 2));
+        // Oldest first
+        let mut logs: Vec<_> = logs.into_values().collect();
+        logs.sort_by_key(|(modified, _)| *modified);
+
+        let mut logs_to_remove = Vec::new();
+
+        // Age policy first, so expired logs don't count towards max_logs
+        if let Some(days) = self.retention_days {
+            let cutoff = std::time::SystemTime::now()
+                - std::time::Duration::from_secs(u64::from(days) * 24 * 60 * 60);
+            let expired = logs.iter().take_while(|(modified, _)| *modified < cutoff).count();
+            logs_to_remove.extend(logs.drain(..expired));
         }
 
-        // Remove oldest logs
-        for entry in files_to_remove {
-            fs::remove_file(entry.path()).await?;
+        let excess = logs.len().saturating_sub(self.max_logs as usize);
+        logs_to_remove.extend(logs.drain(..excess));
+
+        for (_, paths) in logs_to_remove {
+            for path in paths {
+                fs::remove_file(&path)
+                    .await
+                    .context(format!("Failed to remove old log {}", path.display()))?;
+            }
         }
 
         Ok(())
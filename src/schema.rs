@@ -1,9 +1,149 @@
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use sha2::{Digest, Sha256};
 use std::path::Path;
 
+/// Default cap on a single path component, in bytes
+pub const DEFAULT_MAX_FILENAME_LENGTH: usize = 255;
+
+/// Smallest usable cap: room for the `~` and 8-digit hash suffix
+pub const MIN_MAX_FILENAME_LENGTH: usize = 9;
+
+/// Shortens a path component to at most `max_len` bytes, keeping the
+/// extension and appending a short hash of the original so distinct long
+/// names stay distinct. `max_len` must be at least `MIN_MAX_FILENAME_LENGTH`.
+pub fn truncate_component(name: &str, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name.to_string();
+    }
+
+    let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    let mut suffix = format!("~{}{}", &hash[..8], ext);
+    if suffix.len() >= max_len {
+        // The extension alone doesn't fit, keep only the hash
+        suffix = format!("~{}", &hash[..8]);
+    }
+
+    let mut end = max_len.saturating_sub(suffix.len()).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], suffix)
+}
+
+/// A single problem found while validating an asset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    /// Name of the offending field
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Every problem found on one asset of a manifest
+#[derive(Debug, Clone)]
+pub struct AssetErrors {
+    pub id: String,
+    pub errors: Vec<FieldError>,
+}
+
+/// Validates every asset, returning the problems of each invalid one
+pub fn validate_assets(assets: &[DrupalFileAsset]) -> Vec<AssetErrors> {
+    assets
+        .iter()
+        .filter_map(|asset| {
+            let errors = asset.validate();
+            if errors.is_empty() {
+                None
+            } else {
+                Some(AssetErrors {
+                    id: asset.id.clone(),
+                    errors,
+                })
+            }
+        })
+        .collect()
+}
+
+/// A problem found while checking a whole manifest
+#[derive(Debug, Clone, Serialize)]
+pub struct ManifestProblem {
+    /// Asset the problem belongs to, if any
+    pub id: Option<String>,
+    pub field: Option<String>,
+    pub message: String,
+}
+
+/// Checks a manifest as a whole: every asset's fields, duplicate ids, and
+/// distinct assets that would be written to the same local file
+pub fn check_manifest(assets: &[DrupalFileAsset], max_filename_length: usize) -> Vec<ManifestProblem> {
+    let mut problems: Vec<ManifestProblem> = validate_assets(assets)
+        .into_iter()
+        .flat_map(|asset| {
+            let id = asset.id;
+            asset.errors.into_iter().map(move |error| ManifestProblem {
+                id: Some(id.clone()),
+                field: Some(error.field),
+                message: error.message,
+            })
+        })
+        .collect();
+
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+    let mut local_names: HashMap<String, Vec<&str>> = HashMap::new();
+    for asset in assets {
+        *ids.entry(asset.id.as_str()).or_default() += 1;
+        local_names
+            .entry(asset.local_filename(max_filename_length))
+            .or_default()
+            .push(asset.id.as_str());
+    }
+
+    let mut duplicates: Vec<_> = ids.into_iter().filter(|(_, count)| *count > 1).collect();
+    duplicates.sort();
+    for (id, count) in duplicates {
+        problems.push(ManifestProblem {
+            id: Some(id.to_string()),
+            field: Some("id".to_string()),
+            message: format!("Duplicate ID used by {} assets", count),
+        });
+    }
+
+    let mut collisions: Vec<_> = local_names.into_iter().filter(|(_, ids)| ids.len() > 1).collect();
+    collisions.sort();
+    for (name, ids) in collisions {
+        problems.push(ManifestProblem {
+            id: None,
+            field: Some("filename".to_string()),
+            message: format!("Assets {} would all be written to {}", ids.join(", "), name),
+        });
+    }
+
+    problems
+}
+
 /// Represents the source information in the metadata
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct DrupalSource {
     #[serde(rename = "type")]
     pub source_type: String,
@@ -11,7 +151,7 @@ pub struct DrupalSource {
 }
 
 /// Represents a single file asset from Drupal
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct DrupalFileAsset {
     pub id: String,
     pub filename: String,
@@ -27,34 +167,74 @@ pub struct DrupalFileAsset {
     pub changed: i64,
     #[serde(default)]
     pub scheme: String,
+    /// Labels assigned by the manifest producer (e.g. `public`, `archive`)
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Higher priorities are downloaded first; absent means 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    /// Hex SHA-256 of the published content, checked after download
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
 }
 
 impl DrupalFileAsset {
-    /// Validates that the file asset has all required fields and valid values
-    pub fn validate(&self) -> Result<(), String> {
+    /// Validates that the file asset has all required fields and valid
+    /// values, collecting every problem found
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
         if self.id.is_empty() {
-            return Err("Missing ID".to_string());
+            errors.push(FieldError::new("id", "Missing ID"));
         }
         if self.filename.is_empty() {
-            return Err("Missing filename".to_string());
+            errors.push(FieldError::new("filename", "Missing filename"));
         }
         if self.uri.is_empty() {
-            return Err("Missing URI".to_string());
+            errors.push(FieldError::new("uri", "Missing URI"));
         }
         if self.mime.is_empty() {
-            return Err("Missing MIME type".to_string());
+            errors.push(FieldError::new("mime", "Missing MIME type"));
+        }
+        errors
+    }
+
+    /// Scheduling priority, defaulting to 0 when the manifest omits it
+    pub fn priority(&self) -> i32 {
+        self.priority.unwrap_or(0)
+    }
+
+    /// Validates the asset, reporting only the first problem found
+    pub fn validate_first(&self) -> Result<(), String> {
+        match self.validate().into_iter().next() {
+            Some(error) => Err(error.message),
+            None => Ok(()),
         }
-        Ok(())
     }
 
     /// Gets the local destination path for this file
     pub fn get_local_path(&self, base_path: &str) -> String {
-        if self.path.is_empty() {
+        self.get_local_path_with_limit(base_path, DEFAULT_MAX_FILENAME_LENGTH)
+    }
+
+    /// Gets the local destination path, truncating any component longer
+    /// than `max_len` bytes
+    pub fn get_local_path_with_limit(&self, base_path: &str, max_len: usize) -> String {
+        let relative = if self.path.is_empty() {
             // If path is empty, use the filename
-            format!("{}/{}", base_path.trim_end_matches('/'), self.filename)
+            self.filename.as_str()
         } else {
-            format!("{}/{}", base_path.trim_end_matches('/'), self.path.trim_start_matches('/'))
-        }
+            self.path.trim_start_matches('/')
+        };
+        let relative: Vec<String> = relative
+            .split('/')
+            .map(|component| truncate_component(component, max_len))
+            .collect();
+        format!("{}/{}", base_path.trim_end_matches('/'), relative.join("/"))
+    }
+
+    /// Gets the filename to write locally, truncated to `max_len` bytes
+    pub fn local_filename(&self, max_len: usize) -> String {
+        truncate_component(&self.filename, max_len)
     }
 
     /// Checks if the file is an image
@@ -62,29 +242,330 @@ impl DrupalFileAsset {
         self.mime.starts_with("image/")
     }
 
+    /// Checks the asset against tag filters; excludes win over includes
+    pub fn matches_tags(&self, include: &[String], exclude: &[String]) -> bool {
+        if self.tags.iter().any(|tag| exclude.contains(tag)) {
+            return false;
+        }
+        include.is_empty() || self.tags.iter().any(|tag| include.contains(tag))
+    }
+
+    /// Checks the asset's MIME type against glob patterns such as `image/*`
+    /// and its extension against an exclude list; an empty include list
+    /// allows every type
+    pub fn matches_types(&self, include_mime: &[glob::Pattern], exclude_ext: &[String]) -> bool {
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        if let Some(ext) = self.get_extension() {
+            if exclude_ext.contains(&ext) {
+                return false;
+            }
+        }
+        include_mime.is_empty() || include_mime.iter().any(|pattern| pattern.matches_with(&self.mime, options))
+    }
+
     /// Gets the file extension
     pub fn get_extension(&self) -> Option<String> {
         self.filename
-            .rsplit('.')
-            .next()
-            .map(|s| s.to_lowercase())
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_lowercase())
+    }
+}
+
+/// Asset fields a `--field-map` entry may target
+pub const ASSET_FIELDS: &[&str] = &[
+    "id", "filename", "uri", "path", "mime", "size", "created", "changed", "scheme", "tags", "priority", "sha256",
+];
+
+/// Parses one `SOURCE=FIELD` field mapping, e.g. `name=filename`
+pub fn parse_field_mapping(s: &str) -> Result<(String, String), String> {
+    let (source, field) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected SOURCE=FIELD, got '{}'", s))?;
+    let (source, field) = (source.trim(), field.trim());
+    if source.is_empty() {
+        return Err(format!("missing source key in '{}'", s));
+    }
+    if !ASSET_FIELDS.contains(&field) {
+        return Err(format!("unknown asset field '{}' (expected one of {})", field, ASSET_FIELDS.join(", ")));
+    }
+    Ok((source.to_string(), field.to_string()))
+}
+
+/// Renames the keys of a non-Drupal manifest onto the asset model, so teams
+/// can sync from their own JSON without reshaping it first
+#[derive(Debug, Clone, Default)]
+pub struct FieldMap {
+    /// (source key, asset field) pairs, applied in order
+    mappings: Vec<(String, String)>,
+}
+
+impl FieldMap {
+    pub fn new(mappings: Vec<(String, String)>) -> Self {
+        Self { mappings }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Rewrites a manifest (bare array, or object with a `files` array) into
+    /// the form the Drupal parser reads. Each file's keys are renamed, a
+    /// numeric id becomes a string, and a missing `filename`, `id`, `uri` or
+    /// `mime` is derived from the path. An object lacking the wrapper's
+    /// `version`/`generated`/`source` is reduced to its files.
+    pub fn apply(&self, content: &str) -> Result<String, serde_json::Error> {
+        let mut root: serde_json::Value = serde_json::from_str(content)?;
+        let files = match &mut root {
+            serde_json::Value::Array(files) => files,
+            serde_json::Value::Object(object) => match object.get_mut("files") {
+                Some(serde_json::Value::Array(files)) => files,
+                _ => return Ok(content.to_string()),
+            },
+            _ => return Ok(content.to_string()),
+        };
+        for file in files.iter_mut() {
+            if let serde_json::Value::Object(object) = file {
+                self.map_file(object);
+            }
+        }
+
+        if let serde_json::Value::Object(object) = &mut root {
+            if !["version", "generated", "source"].iter().all(|key| object.contains_key(*key)) {
+                root = object.remove("files").unwrap_or_default();
+            }
+        }
+        serde_json::to_string(&root)
+    }
+
+    fn map_file(&self, object: &mut serde_json::Map<String, serde_json::Value>) {
+        for (source, field) in &self.mappings {
+            if let Some(value) = object.remove(source) {
+                object.insert(field.clone(), value);
+            }
+        }
+
+        let text = |object: &serde_json::Map<String, serde_json::Value>, key: &str| {
+            object.get(key).and_then(|value| match value {
+                serde_json::Value::String(text) if !text.is_empty() => Some(text.clone()),
+                serde_json::Value::Number(number) => Some(number.to_string()),
+                _ => None,
+            })
+        };
+        let path = text(object, "path");
+        let filename = text(object, "filename").or_else(|| path.as_deref().and_then(filename_from_path));
+        let id = text(object, "id").or_else(|| path.clone()).or_else(|| filename.clone());
+        let uri = text(object, "uri").or_else(|| path.clone()).or_else(|| filename.clone());
+        let mime = text(object, "mime").or_else(|| filename.as_deref().map(guess_mime));
+
+        for (key, value) in [("id", id), ("filename", filename), ("uri", uri), ("mime", mime)] {
+            if let Some(value) = value {
+                object.insert(key.to_string(), serde_json::Value::String(value));
+            }
+        }
+    }
+}
+
+/// Last segment of a path or URL, ignoring any query or fragment
+fn filename_from_path(path: &str) -> Option<String> {
+    path.split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+/// MIME type guessed from a filename's extension
+fn guess_mime(filename: &str) -> String {
+    mime_guess::from_path(filename).first_or_octet_stream().to_string()
+}
+
+/// A manifest source's lowercase name without any URL query or compression
+/// suffix, for telling formats apart by extension
+fn format_name(source: &str) -> String {
+    let name = source.split(['?', '#']).next().unwrap_or(source).to_lowercase();
+    for suffix in [".gz", ".zst", ".br"] {
+        if let Some(stripped) = name.strip_suffix(suffix) {
+            return stripped.to_string();
+        }
+    }
+    name
+}
+
+/// Whether a manifest source is YAML, by its `.yaml`/`.yml` extension or
+/// a YAML Content-Type
+pub fn is_yaml(source: &str, content_type: Option<&str>) -> bool {
+    let name = format_name(source);
+    name.ends_with(".yaml")
+        || name.ends_with(".yml")
+        || matches!(
+            content_type,
+            Some("application/x-yaml" | "application/yaml" | "text/yaml" | "text/x-yaml")
+        )
+}
+
+/// Re-encodes a YAML manifest as JSON, keeping its structure
+pub fn yaml_to_json(content: &str) -> Result<String, String> {
+    let value: serde_json::Value = serde_yaml::from_str(content).map_err(|e| e.to_string())?;
+    serde_json::to_string(&value).map_err(|e| e.to_string())
+}
+
+/// Whether a manifest source is a CSV inventory, by its `.csv` extension
+pub fn is_csv(source: &str) -> bool {
+    format_name(source).ends_with(".csv")
+}
+
+/// Turns a CSV inventory with a header row into a JSON array of file
+/// objects keyed by column name. Empty cells are left out so the asset
+/// defaults apply, numeric columns become numbers and `tags` is split on `;`.
+pub fn csv_to_json(content: &str) -> Result<String, String> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(content.as_bytes());
+    let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+
+    let mut files = Vec::new();
+    for (index, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| format!("row {}: {}", index + 2, e))?;
+        let mut file = serde_json::Map::new();
+        for (column, cell) in headers.iter().zip(record.iter()) {
+            if cell.is_empty() {
+                continue;
+            }
+            let value = match column {
+                "size" | "created" | "changed" | "priority" => {
+                    let number: i64 = cell
+                        .parse()
+                        .map_err(|_| format!("row {}: {} '{}' is not a number", index + 2, column, cell))?;
+                    serde_json::Value::from(number)
+                }
+                "tags" => cell.split(';').map(|tag| tag.trim()).filter(|tag| !tag.is_empty()).collect(),
+                _ => serde_json::Value::from(cell),
+            };
+            file.insert(column.to_string(), value);
+        }
+        files.push(serde_json::Value::Object(file));
+    }
+    serde_json::to_string(&files).map_err(|e| e.to_string())
+}
+
+/// Whether a manifest is a plain list of URLs or paths, one per line: a
+/// `.txt` source or a `text/plain` response. Anything else is parsed as JSON.
+pub fn is_url_list(source: &str, content_type: Option<&str>) -> bool {
+    format_name(source).ends_with(".txt") || content_type == Some("text/plain")
+}
+
+/// Builds assets from a URL list. Blank lines and `#` comments are ignored,
+/// repeats are dropped, and each entry serves as id, uri and path.
+pub fn parse_url_list(content: &str) -> Vec<DrupalFileAsset> {
+    let mut seen = std::collections::HashSet::new();
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter(|line| seen.insert(line.to_string()))
+        .filter_map(|line| {
+            let filename = filename_from_path(line)?;
+            Some(DrupalFileAsset {
+                id: line.to_string(),
+                mime: guess_mime(&filename),
+                filename,
+                uri: line.to_string(),
+                path: line.to_string(),
+                size: None,
+                created: 0,
+                changed: 0,
+                scheme: String::new(),
+                tags: Vec::new(),
+                priority: None,
+                sha256: None,
+            })
+        })
+        .collect()
+}
+
+/// Include and exclude globs matched against each asset's `path` and
+/// `filename`. Each side is compiled once into a `GlobSet`.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// Compiles the patterns; `*` stays within one path segment, `**` spans several
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, String> {
+        let compile = |patterns: &[String]| -> Result<Option<GlobSet>, String> {
+            if patterns.is_empty() {
+                return Ok(None);
+            }
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(path_glob(pattern)?);
+            }
+            builder.build().map(Some).map_err(|e| e.to_string())
+        };
+        Ok(Self {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    /// Any include match keeps an asset, any exclude match drops it, and
+    /// excludes win. With no includes every asset is kept.
+    pub fn matches(&self, asset: &DrupalFileAsset) -> bool {
+        let hit = |set: &GlobSet| set.is_match(&asset.path) || set.is_match(&asset.filename);
+        if self.exclude.as_ref().is_some_and(hit) {
+            return false;
+        }
+        self.include.as_ref().map_or(true, hit)
+    }
+}
+
+/// Parses one `--include`/`--exclude` pattern
+pub fn path_glob(pattern: &str) -> Result<Glob, String> {
+    GlobBuilder::new(pattern.trim())
+        .literal_separator(true)
+        .build()
+        .map_err(|e| format!("invalid glob '{}': {}", pattern, e))
+}
+
+/// Matches a MIME type against an exact type (`image/png`), a type
+/// wildcard (`image/*`) or `*`
+pub fn mime_matches(pattern: &str, mime: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let pattern = pattern.to_lowercase();
+    let mime = mime.to_lowercase();
+    match pattern.strip_suffix("/*") {
+        Some(prefix) => mime.split('/').next() == Some(prefix),
+        None => mime == pattern,
     }
 }
 
 /// Represents a collection of file assets from Drupal with metadata
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct DrupalFileAssetsWrapper {
     pub version: String,
     pub generated: i64,
     pub source: DrupalSource,
     pub files: Vec<DrupalFileAsset>,
+    /// Directories to create even when no file lands in them
+    #[serde(default)]
+    pub directories: Vec<String>,
 }
 
 impl DrupalFileAssetsWrapper {
-    /// Validates all file assets in the collection
-    pub fn validate(&self) -> Result<(), String> {
+    /// Validates all file assets in the collection, grouped per asset
+    pub fn validate(&self) -> Vec<AssetErrors> {
+        validate_assets(&self.files)
+    }
+
+    /// Validates the collection, reporting only the first problem found
+    pub fn validate_first(&self) -> Result<(), String> {
         for asset in &self.files {
-            if let Err(e) = asset.validate() {
+            if let Err(e) = asset.validate_first() {
                 return Err(format!("Asset {} validation failed: {}", asset.id, e));
             }
         }
@@ -114,6 +595,67 @@ impl DrupalFileAssetsWrapper {
     }
 }
 
+/// Changes to apply on top of a base manifest, matched by asset id
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ManifestPatch {
+    /// New assets; an existing id is replaced
+    #[serde(default)]
+    pub add: Vec<DrupalFileAsset>,
+    /// Replacement assets; an unknown id is added
+    #[serde(default)]
+    pub update: Vec<DrupalFileAsset>,
+    /// Ids of assets to drop
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// What applying a patch changed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PatchStats {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+impl ManifestPatch {
+    /// Applies removals, then updates and additions, keeping the base order
+    /// and appending new assets in patch order
+    pub fn apply(&self, base: Vec<DrupalFileAsset>) -> (Vec<DrupalFileAsset>, PatchStats) {
+        let mut stats = PatchStats::default();
+
+        let before = base.len();
+        let mut assets: Vec<DrupalFileAsset> = base
+            .into_iter()
+            .filter(|asset| !self.remove.contains(&asset.id))
+            .collect();
+        stats.removed = before - assets.len();
+
+        for asset in self.update.iter().chain(&self.add) {
+            match assets.iter_mut().find(|existing| existing.id == asset.id) {
+                Some(existing) => {
+                    *existing = asset.clone();
+                    stats.updated += 1;
+                }
+                None => {
+                    assets.push(asset.clone());
+                    stats.added += 1;
+                }
+            }
+        }
+
+        (assets, stats)
+    }
+}
+
+/// Assets and declared directories read from a manifest
+#[derive(Debug, Default)]
+pub struct Manifest {
+    pub files: Vec<DrupalFileAsset>,
+    pub directories: Vec<String>,
+    /// Unix time the manifest was generated; only the wrapper form has it
+    pub generated: Option<i64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum DrupalFileAssetsResponse {
@@ -121,6 +663,11 @@ pub enum DrupalFileAssetsResponse {
     Array(Vec<DrupalFileAsset>),
 }
 
+/// JSON Schema of the manifest wrapper, the format `sync` reads
+pub fn manifest_json_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(DrupalFileAssetsWrapper)
+}
+
 impl DrupalFileAssetsResponse {
     pub fn into_vec(self) -> Vec<DrupalFileAsset> {
         match self {
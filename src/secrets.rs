@@ -0,0 +1,87 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::fs;
+
+/// Credentials for one endpoint
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Credentials {
+    /// Username for basic auth
+    pub username: Option<String>,
+    /// Password for basic auth
+    pub password: Option<String>,
+    /// Bearer token, used when no username/password pair is set
+    pub token: Option<String>,
+}
+
+impl Credentials {
+    /// Keeps these credentials when any is set, otherwise uses `fallback`.
+    /// Sources are never mixed, so a lower-precedence password can't
+    /// displace a higher-precedence token.
+    pub fn or(self, fallback: Credentials) -> Credentials {
+        if self.is_empty() {
+            fallback
+        } else {
+            self
+        }
+    }
+
+    /// Checks whether any credential is set
+    pub fn is_empty(&self) -> bool {
+        self.username.is_none() && self.password.is_none() && self.token.is_none()
+    }
+
+    /// Applies basic auth, or bearer auth when only a token is set
+    pub fn apply(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match (&self.username, &self.password, &self.token) {
+            (Some(username), Some(password), _) => request.basic_auth(username, Some(password)),
+            (_, _, Some(token)) => request.bearer_auth(token),
+            _ => request,
+        }
+    }
+}
+
+/// Credentials kept in a separate secrets file (JSON or TOML)
+#[derive(Debug, Default, Deserialize)]
+pub struct Secrets {
+    /// Credentials for the metadata source
+    #[serde(default)]
+    pub source: Credentials,
+    /// Credentials for file downloads
+    #[serde(default)]
+    pub download: Credentials,
+}
+
+impl Secrets {
+    /// Loads a secrets file, warning when it's readable by other users
+    pub async fn load(path: &Path) -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path)
+                .await
+                .context(format!("Failed to read secrets file {}", path.display()))?
+                .permissions()
+                .mode();
+            if mode & 0o077 != 0 {
+                log::warn!(
+                    "secrets file {} is accessible by other users (mode {:o}); restrict it with chmod 600",
+                    path.display(),
+                    mode & 0o777
+                );
+            }
+        }
+
+        let content = fs::read_to_string(path)
+            .await
+            .context(format!("Failed to read secrets file {}", path.display()))?;
+        let secrets = if path.extension().map_or(false, |ext| ext == "toml") {
+            toml::from_str(&content)
+                .context(format!("Failed to parse secrets file {}", path.display()))?
+        } else {
+            serde_json::from_str(&content)
+                .context(format!("Failed to parse secrets file {}", path.display()))?
+        };
+        Ok(secrets)
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+use tokio::time::sleep;
+
+/// Smallest delay applied after a host first answers 429
+const MIN_BACKOFF_MS: u64 = 250;
+/// Upper bound on the adaptive delay for a host
+const MAX_DELAY_MS: u64 = 60_000;
+
+/// Adapted request rate for one host
+#[derive(Debug, Clone)]
+pub struct HostRate {
+    pub host: String,
+    /// Extra delay before each request, on top of `download_delay`
+    pub delay_ms: u64,
+    /// Requests allowed in flight at once
+    pub concurrency: usize,
+    /// Requests sent to the host
+    pub requests: u64,
+    /// Responses that were 429 Too Many Requests
+    pub throttled: u64,
+    in_flight: usize,
+    successes: usize,
+}
+
+impl HostRate {
+    fn new(host: &str, concurrency: usize) -> Self {
+        Self {
+            host: host.to_string(),
+            delay_ms: 0,
+            concurrency,
+            requests: 0,
+            throttled: 0,
+            in_flight: 0,
+            successes: 0,
+        }
+    }
+}
+
+/// Per-host politeness controller driven by 429 responses.
+///
+/// A 429 doubles the host's delay (or uses its `Retry-After`) and halves
+/// its concurrency. Successful responses relax the delay and add back one
+/// slot of concurrency per window of successes, up to `max_concurrency`.
+#[derive(Clone)]
+pub struct AdaptiveThrottle {
+    max_concurrency: usize,
+    hosts: Arc<Mutex<HashMap<String, HostRate>>>,
+    released: Arc<Notify>,
+    bandwidth: Option<BandwidthLimiter>,
+}
+
+impl AdaptiveThrottle {
+    pub fn new(max_concurrency: usize) -> Self {
+        Self {
+            max_concurrency: max_concurrency.max(1),
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+            released: Arc::new(Notify::new()),
+            bandwidth: None,
+        }
+    }
+
+    /// Caps the combined download rate of every host at `bytes_per_sec`
+    pub fn with_bandwidth(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.bandwidth = bytes_per_sec.map(BandwidthLimiter::new);
+        self
+    }
+
+    /// Accounts for `bytes` just received, pausing when over the bandwidth cap
+    pub async fn consume(&self, bytes: u64) {
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.consume(bytes).await;
+        }
+    }
+
+    /// Waits for a free slot on `host`, then for its current delay
+    pub async fn acquire(&self, host: &str) {
+        loop {
+            // Created before checking so a release in between isn't missed
+            let released = self.released.notified();
+            let delay = {
+                let mut hosts = self.hosts.lock().await;
+                let rate = hosts
+                    .entry(host.to_string())
+                    .or_insert_with(|| HostRate::new(host, self.max_concurrency));
+                if rate.in_flight < rate.concurrency {
+                    rate.in_flight += 1;
+                    rate.requests += 1;
+                    Some(rate.delay_ms)
+                } else {
+                    None
+                }
+            };
+
+            match delay {
+                Some(0) => return,
+                Some(delay) => {
+                    sleep(Duration::from_millis(delay)).await;
+                    return;
+                }
+                None => released.await,
+            }
+        }
+    }
+
+    /// Frees the slot taken by `acquire` after a response that wasn't a 429
+    pub async fn release(&self, host: &str, success: bool) {
+        let mut hosts = self.hosts.lock().await;
+        if let Some(rate) = hosts.get_mut(host) {
+            rate.in_flight = rate.in_flight.saturating_sub(1);
+            if success {
+                rate.delay_ms = rate.delay_ms * 3 / 4;
+                if rate.delay_ms < 10 {
+                    rate.delay_ms = 0;
+                }
+                rate.successes += 1;
+                if rate.successes >= rate.concurrency && rate.concurrency < self.max_concurrency {
+                    rate.concurrency += 1;
+                    rate.successes = 0;
+                }
+            }
+        }
+        drop(hosts);
+        self.released.notify_waiters();
+    }
+
+    /// Frees the slot taken by `acquire` after a 429 and backs the host off
+    pub async fn throttled(&self, host: &str, retry_after: Option<Duration>) {
+        let mut hosts = self.hosts.lock().await;
+        if let Some(rate) = hosts.get_mut(host) {
+            rate.in_flight = rate.in_flight.saturating_sub(1);
+            rate.throttled += 1;
+            rate.successes = 0;
+            rate.concurrency = (rate.concurrency / 2).max(1);
+            let backoff = (rate.delay_ms * 2).max(MIN_BACKOFF_MS);
+            let requested = retry_after.map(|d| d.as_millis() as u64).unwrap_or(0);
+            rate.delay_ms = backoff.max(requested).min(MAX_DELAY_MS);
+        }
+        drop(hosts);
+        self.released.notify_waiters();
+    }
+
+    /// Returns the adapted rate of every host contacted so far
+    pub async fn host_rates(&self) -> Vec<HostRate> {
+        let mut rates: Vec<HostRate> = self.hosts.lock().await.values().cloned().collect();
+        rates.sort_by(|a, b| a.host.cmp(&b.host));
+        rates
+    }
+}
+
+/// Token bucket shared by every download, refilled at `rate` bytes per
+/// second with up to one second of burst.
+///
+/// This smooths the aggregate rate rather than enforcing a hard cap: a
+/// chunk is always accepted whole and the receiver sleeps off the debt
+/// afterwards, so short bursts above the rate are possible.
+#[derive(Clone)]
+pub struct BandwidthLimiter {
+    rate: f64,
+    bucket: Arc<Mutex<(f64, Instant)>>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        Self {
+            rate,
+            bucket: Arc::new(Mutex::new((rate, Instant::now()))),
+        }
+    }
+
+    /// Takes `bytes` from the bucket, sleeping while it is in debt
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            if *tokens < 0.0 {
+                Duration::from_secs_f64(-*tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}